@@ -0,0 +1,151 @@
+extern crate clap;
+extern crate rustc_serialize;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+
+use clap::ArgMatches;
+use rustc_serialize::json::Json;
+
+/// A name for a list of prefixes, e.g. `basemap` for `land`, `points` & `roads`, so clients can
+/// ask for `/basemap/index.json` instead of `/land__points__roads/index.json`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alias {
+    pub prefixes: Vec<String>,
+    /// Used as the `name` in the TileJSON, if given
+    pub name: Option<String>,
+    /// Used as the `attribution` in the TileJSON, if given
+    pub attribution: Option<String>,
+}
+
+/// The aliases from `--aliases`, and whether prefixes can be joined with `__` in URLs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Aliases {
+    aliases: HashMap<String, Alias>,
+    allow_concatenation: bool,
+}
+
+impl Aliases {
+    /// No aliases, and `__` is allowed
+    pub fn none() -> Self {
+        Aliases{ aliases: HashMap::new(), allow_concatenation: true }
+    }
+
+    /// Construct from the `--aliases` & `--no-concatenation` command line options. Exits if the
+    /// aliases file is invalid.
+    pub fn from_options(options: &ArgMatches) -> Self {
+        let mut aliases = match options.value_of("aliases") {
+            None => Aliases::none(),
+            Some(filename) => match Aliases::load(filename) {
+                Ok(a) => a,
+                Err(e) => {
+                    println!("Invalid --aliases file {:?}: {}", filename, e);
+                    ::std::process::exit(1);
+                },
+            },
+        };
+        aliases.allow_concatenation = ! options.is_present("no_concatenation");
+        aliases
+    }
+
+    fn load(filename: &str) -> Result<Self, String> {
+        let mut file = try!(File::open(filename).map_err(|e| e.to_string()));
+        let mut contents = String::new();
+        try!(file.read_to_string(&mut contents).map_err(|e| e.to_string()));
+        Aliases::parse(&contents)
+    }
+
+    /// Parse the aliases from JSON like:
+    ///
+    ///     {"basemap": {"prefixes": ["land", "points"], "name": "Basemap", "attribution": "..."},
+    ///      "roads": ["roads_low", "roads_high"]}
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let json = try!(Json::from_str(contents).map_err(|e| e.to_string()));
+        let object = try!(json.as_object().ok_or("Not a JSON object".to_string()));
+
+        let mut aliases = HashMap::new();
+        for (alias, definition) in object.iter() {
+            if alias.is_empty() || alias.contains("__") || ! alias.chars().all(|c| match c { 'a' ... 'z' | 'A' ... 'Z' | '0' ... '9' | '_' | '-' => true, _ => false }) {
+                return Err(format!("Invalid alias name {:?}, it can only have letters, numbers, _ and -, and no __", alias));
+            }
+
+            let (prefixes, name, attribution) = match *definition {
+                Json::Array(_) => (Some(definition), None, None),
+                Json::Object(ref o) => (o.get("prefixes"), o.get("name"), o.get("attribution")),
+                _ => (None, None, None),
+            };
+            let prefixes: Vec<String> = try!(prefixes.and_then(|p| p.as_array())
+                                            .and_then(|p| p.iter().map(|x| x.as_string().map(|s| s.to_string())).collect::<Option<Vec<_>>>())
+                                            .ok_or(format!("Alias {:?} needs a list of prefixes", alias)));
+            if prefixes.is_empty() {
+                return Err(format!("Alias {:?} has no prefixes", alias));
+            }
+            let as_string = |value: Option<&Json>, key: &str| -> Result<Option<String>, String> {
+                match value {
+                    None => Ok(None),
+                    Some(v) => v.as_string().map(|s| Some(s.to_string())).ok_or(format!("The {} of alias {:?} isn't a string", key, alias)),
+                }
+            };
+
+            aliases.insert(alias.to_string(), Alias{ prefixes: prefixes, name: try!(as_string(name, "name")), attribution: try!(as_string(attribution, "attribution")) });
+        }
+
+        Ok(Aliases{ aliases: aliases, allow_concatenation: true })
+    }
+
+    pub fn get(&self, alias: &str) -> Option<&Alias> {
+        self.aliases.get(alias)
+    }
+
+    /// The names of all the aliases, sorted
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.aliases.keys().map(|a| a.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    /// The prefixes this part of a URL (e.g. `basemap`, `land` or `land__points`) refers to.
+    /// `None` if it uses `__` when that isn't allowed.
+    pub fn resolve(&self, prefix: &str) -> Option<Vec<String>> {
+        if let Some(alias) = self.aliases.get(prefix) {
+            return Some(alias.prefixes.clone());
+        }
+        let parts: Vec<&str> = prefix.split("__").filter(|x| ! x.is_empty()).collect();
+        if parts.len() > 1 && ! self.allow_concatenation {
+            return None;
+        }
+        // Aliases can be joined with other prefixes too
+        Some(parts.into_iter().flat_map(|part| match self.aliases.get(part) {
+            Some(alias) => alias.prefixes.clone(),
+            None => vec![part.to_string()],
+        }).collect())
+    }
+}
+
+mod test {
+    #[test]
+    fn test_aliases() {
+        use super::{Aliases, Alias};
+
+        let aliases = Aliases::parse(r#"{"basemap": {"prefixes": ["land", "points"], "name": "Basemap", "attribution": "Us"}, "roads": ["roads_low", "roads_high"]}"#).unwrap();
+        assert_eq!(aliases.get("basemap"), Some(&Alias{ prefixes: vec!["land".to_string(), "points".to_string()], name: Some("Basemap".to_string()), attribution: Some("Us".to_string()) }));
+        assert_eq!(aliases.get("roads").unwrap().name, None);
+
+        assert_eq!(aliases.resolve("basemap"), Some(vec!["land".to_string(), "points".to_string()]));
+        assert_eq!(aliases.resolve("water"), Some(vec!["water".to_string()]));
+        assert_eq!(aliases.resolve("basemap__roads__water"), Some(vec!["land", "points", "roads_low", "roads_high", "water"].into_iter().map(|s| s.to_string()).collect()));
+
+        let mut no_concatenation = aliases.clone();
+        no_concatenation.allow_concatenation = false;
+        assert_eq!(no_concatenation.resolve("basemap"), Some(vec!["land".to_string(), "points".to_string()]));
+        assert_eq!(no_concatenation.resolve("land__points"), None);
+
+        assert!(Aliases::parse("[]").is_err());
+        assert!(Aliases::parse(r#"{"a__b": ["land"]}"#).is_err());
+        assert!(Aliases::parse(r#"{"a b": ["land"]}"#).is_err());
+        assert!(Aliases::parse(r#"{"basemap": []}"#).is_err());
+        assert!(Aliases::parse(r#"{"basemap": {"name": "Basemap"}}"#).is_err());
+        assert!(Aliases::parse(r#"{"basemap": {"prefixes": ["land"], "name": 1}}"#).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+extern crate hyper;
+extern crate clap;
+
+use hyper::header::Headers;
+
+use clap::ArgMatches;
+
+/// Which origins are allowed to make cross-origin requests
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AllowedOrigins {
+    /// No CORS headers are sent at all (`--no-cors`)
+    None,
+    /// Any origin (`*`)
+    Any,
+    /// Only these origins, e.g. `https://example.com`
+    List(Vec<String>),
+}
+
+/// The CORS headers sent with every response, and how `OPTIONS` (preflight) requests are
+/// answered. One of these is shared between all threads.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorsPolicy {
+    origins: AllowedOrigins,
+    credentials: bool,
+    expose_headers: Vec<String>,
+    max_age: u32,
+}
+
+/// Is this a valid HTTP header name (a "token")?
+fn is_header_name(name: &str) -> bool {
+    ! name.is_empty() && name.chars().all(|c| match c { 'a' ... 'z' | 'A' ... 'Z' | '0' ... '9' | '-' | '_' | '!' | '#' | '$' | '%' | '&' | '\'' | '*' | '+' | '.' | '^' | '`' | '|' | '~' => true, _ => false })
+}
+
+/// Split a comma separated list (of header names)
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',').map(|h| h.trim().to_string()).filter(|h| ! h.is_empty()).collect()
+}
+
+impl CorsPolicy {
+    /// `origins` is the list of allowed origins, `*` for any. An empty list means no CORS headers
+    /// are sent.
+    pub fn new(origins: &[&str], credentials: bool, expose_headers: &[String], max_age: u32) -> Result<Self, String> {
+        let origins = if origins.is_empty() {
+            AllowedOrigins::None
+        } else if origins.contains(&"*") {
+            if credentials {
+                return Err("Credentials can only be allowed for a list of origins, not *".to_string());
+            }
+            AllowedOrigins::Any
+        } else {
+            let mut list = Vec::with_capacity(origins.len());
+            for origin in origins {
+                let origin = origin.trim_right_matches('/');
+                let host = if origin.starts_with("https://") { &origin[8..] } else if origin.starts_with("http://") { &origin[7..] } else { "" };
+                if host.is_empty() || host.contains('/') {
+                    return Err(format!("Invalid CORS origin {:?}, it should look like https://example.com", origin));
+                }
+                list.push(origin.to_string());
+            }
+            AllowedOrigins::List(list)
+        };
+        if let Some(header) = expose_headers.iter().find(|h| ! is_header_name(h)) {
+            return Err(format!("Invalid header name {:?}", header));
+        }
+
+        Ok(CorsPolicy{ origins: origins, credentials: credentials, expose_headers: expose_headers.to_vec(), max_age: max_age })
+    }
+
+    /// Construct from the `--cors-*` & `--no-cors` command line options. Exits if they are
+    /// invalid.
+    pub fn from_options(options: &ArgMatches) -> Self {
+        let origins: Vec<&str> = if options.is_present("no_cors") {
+            Vec::new()
+        } else {
+            options.values_of("cors_origin").map(|o| o.collect()).unwrap_or(vec!["*"])
+        };
+        let expose_headers = split_list(options.value_of("cors_expose_headers").unwrap_or(""));
+        let max_age = options.value_of("cors_max_age").unwrap().parse().unwrap_or_else(|_| {
+            println!("Invalid --cors-max-age, it must be a number of seconds");
+            ::std::process::exit(1);
+        });
+        CorsPolicy::new(&origins, options.is_present("cors_credentials"), &expose_headers, max_age).unwrap_or_else(|e| {
+            println!("Invalid CORS options: {}", e);
+            ::std::process::exit(1);
+        })
+    }
+
+    /// The `Access-Control-Allow-Origin` to send for a request from `origin` (the request's
+    /// `Origin` header), if it is allowed.
+    fn allowed_origin(&self, origin: Option<&str>) -> Option<String> {
+        match self.origins {
+            AllowedOrigins::None => None,
+            AllowedOrigins::Any => Some("*".to_string()),
+            AllowedOrigins::List(ref list) => origin.and_then(|o| list.iter().find(|allowed| *allowed == o)).cloned(),
+        }
+    }
+
+    /// The JSONP callback to use, if JSONP is allowed. A `<script>` tag can load JSONP from any
+    /// origin, so it's only allowed if CORS allows any origin too.
+    pub fn jsonp_callback(&self, callback: &Option<String>) -> Option<String> {
+        if self.origins == AllowedOrigins::Any { callback.clone() } else { None }
+    }
+
+    /// Add the CORS headers for a request with these headers to a response
+    pub fn apply(&self, request_headers: &Headers, response_headers: &mut Headers) {
+        if let AllowedOrigins::List(_) = self.origins {
+            // The response depends on the Origin, so caches need to know
+            response_headers.set_raw("Vary", vec![b"Origin".to_vec()]);
+        }
+        let origin = request_headers.get_raw("Origin").and_then(|o| o.get(0)).and_then(|o| ::std::str::from_utf8(o).ok());
+        let allowed_origin = match self.allowed_origin(origin) {
+            None => { return; },
+            Some(o) => o,
+        };
+
+        response_headers.set_raw("Access-Control-Allow-Origin", vec![allowed_origin.into_bytes()]);
+        if self.credentials {
+            response_headers.set_raw("Access-Control-Allow-Credentials", vec![b"true".to_vec()]);
+        }
+        if ! self.expose_headers.is_empty() {
+            response_headers.set_raw("Access-Control-Expose-Headers", vec![self.expose_headers.join(", ").into_bytes()]);
+        }
+    }
+
+    /// Add the headers for the response to an `OPTIONS` (preflight) request. Only `GET` & `HEAD`
+    /// are allowed, with any (valid) request headers the client asks for.
+    pub fn apply_preflight(&self, request_headers: &Headers, response_headers: &mut Headers) {
+        response_headers.set_raw("Allow", vec![b"GET, HEAD, OPTIONS".to_vec()]);
+        self.apply(request_headers, response_headers);
+        if response_headers.get_raw("Access-Control-Allow-Origin").is_none() {
+            return;
+        }
+
+        response_headers.set_raw("Access-Control-Allow-Methods", vec![b"GET, HEAD, OPTIONS".to_vec()]);
+        let requested_headers = request_headers.get_raw("Access-Control-Request-Headers").and_then(|h| h.get(0)).and_then(|h| ::std::str::from_utf8(h).ok()).map(split_list).unwrap_or(Vec::new());
+        let requested_headers: Vec<String> = requested_headers.into_iter().filter(|h| is_header_name(h)).collect();
+        if ! requested_headers.is_empty() {
+            response_headers.set_raw("Access-Control-Allow-Headers", vec![requested_headers.join(", ").into_bytes()]);
+        }
+        response_headers.set_raw("Access-Control-Max-Age", vec![self.max_age.to_string().into_bytes()]);
+    }
+}
+
+mod test {
+    #[test]
+    fn test_cors_policy() {
+        use super::CorsPolicy;
+        use hyper::header::Headers;
+
+        let header = |headers: &Headers, name: &str| headers.get_raw(name).map(|v| String::from_utf8(v[0].clone()).unwrap());
+        let request = |origin: &str| {
+            let mut headers = Headers::new();
+            headers.set_raw("Origin", vec![origin.as_bytes().to_vec()]);
+            headers.set_raw("Access-Control-Request-Headers", vec![b"X-Foo, bad header".to_vec()]);
+            headers
+        };
+
+        let any = CorsPolicy::new(&["*"], false, &[], 600).unwrap();
+        let mut response = Headers::new();
+        any.apply(&Headers::new(), &mut response);
+        assert_eq!(header(&response, "Access-Control-Allow-Origin"), Some("*".to_string()));
+        assert_eq!(header(&response, "Vary"), None);
+        assert_eq!(header(&response, "Access-Control-Allow-Credentials"), None);
+
+        let list = CorsPolicy::new(&["https://example.com/", "http://localhost:8000"], true, &["X-Cache".to_string()], 600).unwrap();
+        let mut response = Headers::new();
+        list.apply(&request("https://example.com"), &mut response);
+        assert_eq!(header(&response, "Access-Control-Allow-Origin"), Some("https://example.com".to_string()));
+        assert_eq!(header(&response, "Access-Control-Allow-Credentials"), Some("true".to_string()));
+        assert_eq!(header(&response, "Access-Control-Expose-Headers"), Some("X-Cache".to_string()));
+        assert_eq!(header(&response, "Vary"), Some("Origin".to_string()));
+
+        let mut response = Headers::new();
+        list.apply_preflight(&request("https://evil.example.com"), &mut response);
+        assert_eq!(header(&response, "Access-Control-Allow-Origin"), None);
+        assert_eq!(header(&response, "Access-Control-Allow-Methods"), None);
+        assert_eq!(header(&response, "Allow"), Some("GET, HEAD, OPTIONS".to_string()));
+
+        let mut response = Headers::new();
+        list.apply_preflight(&request("http://localhost:8000"), &mut response);
+        assert_eq!(header(&response, "Access-Control-Allow-Origin"), Some("http://localhost:8000".to_string()));
+        assert_eq!(header(&response, "Access-Control-Allow-Methods"), Some("GET, HEAD, OPTIONS".to_string()));
+        assert_eq!(header(&response, "Access-Control-Allow-Headers"), Some("X-Foo".to_string()));
+        assert_eq!(header(&response, "Access-Control-Max-Age"), Some("600".to_string()));
+
+        let none = CorsPolicy::new(&[], false, &[], 600).unwrap();
+        let mut response = Headers::new();
+        none.apply(&request("https://example.com"), &mut response);
+        assert_eq!(response.len(), 0);
+
+        // JSONP gets around CORS, so is only allowed for any origin
+        let callback = Some("cb".to_string());
+        assert_eq!(any.jsonp_callback(&callback), callback);
+        assert_eq!(list.jsonp_callback(&callback), None);
+        assert_eq!(none.jsonp_callback(&callback), None);
+
+        assert!(CorsPolicy::new(&["*"], true, &[], 600).is_err());
+        assert!(CorsPolicy::new(&["example.com"], false, &[], 600).is_err());
+        assert!(CorsPolicy::new(&["https://example.com/maps"], false, &[], 600).is_err());
+        assert!(CorsPolicy::new(&["*"], false, &["E Tag".to_string()], 600).is_err());
+    }
+}
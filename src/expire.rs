@@ -3,6 +3,7 @@ extern crate hyper;
 extern crate slippy_map_tiles;
 extern crate simple_parallel;
 extern crate iter_progress;
+extern crate libflate;
 
 use std::fs;
 use std::path::{Path, PathBuf};
@@ -11,27 +12,111 @@ use std::io::prelude::*;
 use std::os::unix::fs::MetadataExt;
 #[allow(deprecated)]
 use std::os::unix::raw::time_t;
-use std::thread::sleep;
-use std::time::Duration;
+use std::io;
+use std::time::{Duration, Instant, SystemTime};
+use std::collections::{HashSet, HashMap};
+use std::cmp::{min, max};
 
 use clap::ArgMatches;
 
 use slippy_map_tiles::Tile;
 use iter_progress::ProgressableIter;
 
-use utils::download_url_and_save_to_file;
+use libflate::gzip::Encoder;
+
+use upstream::Upstream;
+use watch::{DirectoryWatcher, backoff};
+use utils::{download_url_and_save_to_file, url_host, HttpOptions, RefreshStats, DirectoryLayout, SaveOutcome, delete_tile, is_stale, mark_stale};
+
+/// Tiles are only expanded to their children this many zooms past them (4^8 = 65536 children)
+const MAX_CHILD_ZOOMS: u8 = 8;
+
+/// The highest zoom that `--expire-min-zoom`/`--expire-max-zoom` can be
+const MAX_ZOOM: u8 = 30;
+
+/// With `--once`, wait at most this long for files which are still being written
+const ONCE_MAX_SETTLE_SECS: u64 = 60;
+
+/// One set of tiles which is kept up to date from its own upstream & expire directory
+struct ExpireSource {
+    /// Prefix (i.e. subdirectory) of the tile cache, if using prefixes
+    prefix: Option<String>,
+    /// Root directory of these tiles
+    tile_path: String,
+    upstream: Upstream,
+    expire_directory: PathBuf,
+    /// How many times in a row reading the expire directory has failed
+    consecutive_errors: u32,
+    /// When to next try reading the expire directory, after an error
+    retry_at: Option<Instant>,
+    /// Expire files which couldn't be processed, with how many times in a row that has happened,
+    /// and when to next try them
+    failed_files: HashMap<PathBuf, (u32, Instant)>,
+}
+
+/// What to do with an expired tile
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExpireMode {
+    /// Download it again from upstream now
+    Refresh,
+    /// Delete it, so that `serve` downloads it on the next request
+    Delete,
+    /// Mark it as stale, so that `serve` sends the old tile on the next request, and refreshes it
+    /// in the background
+    MarkStale,
+}
+
+impl ExpireMode {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "refresh" => Some(ExpireMode::Refresh),
+            "delete" => Some(ExpireMode::Delete),
+            "mark-stale" => Some(ExpireMode::MarkStale),
+            _ => None,
+        }
+    }
+}
+
+/// What to do with processed (`done-*`) expire files once they are old enough
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DoneAction {
+    Delete,
+    /// gzip them, in the same directory
+    Compress,
+    /// Move them to this directory (in a subdirectory for the prefix, if there is one)
+    Archive(PathBuf),
+}
+
+/// How long to keep processed expire files, and what to do with them after that
+#[derive(Debug)]
+struct Retention {
+    max_age: Duration,
+    action: DoneAction,
+}
+
+/// Settings which apply to all the ExpireSources
+struct ExpireSettings {
+    mode: ExpireMode,
+    path_format: DirectoryLayout,
+    changed_tiles_list: Option<String>,
+    min_zoom: Option<u8>,
+    max_zoom: Option<u8>,
+    retention: Option<Retention>,
+}
 
 #[allow(deprecated)]
-fn dl_tile_if_older(tile: Tile, tc_path: &str, upstream_url: &str, expiry_mtime: time_t) {
+fn dl_tile_if_older(tile: Tile, source: &ExpireSource, mode: ExpireMode, path_format: DirectoryLayout, expiry_mtime: time_t, http: &HttpOptions, stats: &RefreshStats) {
     let x = tile.x();
     let y = tile.y();
     let z = tile.zoom();
+    let upstream = &source.upstream;
 
-    let path = format!("{}/{}", tc_path, tile.tc_path("pbf"));
+    let path = format!("{}/{}", source.tile_path, path_format.tile_path(&tile, "pbf"));
     let this_tile_tc_path = Path::new(&path);
 
     let should_dl = if ! this_tile_tc_path.exists() {
-            true
+            // Tiles we don't have only need to be downloaded when refreshing
+            mode == ExpireMode::Refresh
         } else {
             let mtime = match this_tile_tc_path.metadata() {
                 Err(e) => { println!("Error when trying to get tile metadata: {:?}", e); return; },
@@ -41,15 +126,129 @@ fn dl_tile_if_older(tile: Tile, tc_path: &str, upstream_url: &str, expiry_mtime:
         };
 
     if should_dl {
-        download_url_and_save_to_file(&format!("{}/{}/{}/{}.pbf", upstream_url, z, x, y), this_tile_tc_path).unwrap_or_else(|e| {
-            println!("Error occured when downloading {}/{}/{}: {:?}", z, x, y, e);
-        });
+        let result = match mode {
+            ExpireMode::Refresh => {
+                let url = upstream.tile_url(z, x, y);
+                download_url_and_save_to_file(&url, this_tile_tc_path, 10, http, upstream.headers_for(&url))
+            },
+            ExpireMode::Delete => delete_tile(this_tile_tc_path).map(|_| SaveOutcome::Invalidated),
+            ExpireMode::MarkStale => {
+                if is_stale(this_tile_tc_path) {
+                    // Already marked, nothing has changed
+                    return;
+                }
+                mark_stale(this_tile_tc_path).map(|_| SaveOutcome::Invalidated)
+            },
+        };
+        stats.record(z, x, y, &result);
+        if let Err(e) = result {
+            println!("Error occured when expiring {}/{}/{}: {:?}", z, x, y, e);
+        }
+    }
+
+}
+
+/// Parse one line of an osm2pgsql or imposm3 expire file, which is `Z/X/Y` (imposm3 tiles can
+/// also have a leading `/` or file extension). Empty lines and `#` comments are `Ok(None)`.
+fn parse_expire_line(line: &str) -> Result<Option<(u8, u32, u32)>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let tile = line.trim_left_matches('/');
+    let tile = match tile.rfind('.') { Some(i) => &tile[..i], None => tile };
+    let parts: Vec<&str> = tile.split('/').collect();
+    if parts.len() != 3 {
+        return Err(format!("Not of the form Z/X/Y: {:?}", line));
+    }
+
+    let z: u8 = try!(parts[0].parse().map_err(|_| format!("Invalid zoom in {:?}", line)));
+    let x: u32 = try!(parts[1].parse().map_err(|_| format!("Invalid x in {:?}", line)));
+    let y: u32 = try!(parts[2].parse().map_err(|_| format!("Invalid y in {:?}", line)));
+    if z > 30 {
+        return Err(format!("Zoom too large in {:?}", line));
+    }
+    let max_xy = 1u64 << z;
+    if x as u64 >= max_xy || y as u64 >= max_xy {
+        return Err(format!("x/y out of range for zoom in {:?}", line));
     }
 
+    Ok(Some((z, x, y)))
+}
+
+/// Parse all the lines of an expire file. Invalid lines are skipped (with a warning)
+fn parse_expire_lines(lines: &[String], filename: &str) -> Vec<(u8, u32, u32)> {
+    lines.iter().enumerate().filter_map(|(i, line)| {
+        match parse_expire_line(line) {
+            Ok(tile) => tile,
+            Err(e) => {
+                println!("Warning: {}:{}: skipping invalid line. {}", filename, i+1, e);
+                None
+            },
+        }
+    }).collect()
 }
 
-fn get_expire_filenames(expire_directory: &Path) -> Result<Vec<PathBuf>, ()> {
-    let entries = try!(expire_directory.read_dir().map_err(|_| ()));
+/// osm2pgsql only lists tiles on one zoom. This expands those tiles to all the parent tiles down to
+/// `min_zoom` and all the child tiles up to `max_zoom`. Tiles above `max_zoom` are replaced by
+/// their parent on `max_zoom`, and tiles below `min_zoom` by their children on `min_zoom`. If
+/// neither is given, the tiles are returned as is (without duplicates).
+///
+/// Children are only added for `MAX_CHILD_ZOOMS` zooms past a tile, since every zoom is 4 times as
+/// many tiles. Tiles more than that below `min_zoom` are skipped.
+fn propagate_zooms(tiles: &[(u8, u32, u32)], min_zoom: Option<u8>, max_zoom: Option<u8>) -> Vec<(u8, u32, u32)> {
+    let mut seen = HashSet::new();
+    let mut result = Vec::with_capacity(tiles.len());
+    let mut warned = false;
+    let mut warned_skipped = false;
+
+    for &(z, x, y) in tiles {
+        let highest = max_zoom.unwrap_or(z);
+        let lowest = min_zoom.unwrap_or(min(z, highest));
+        let wanted_highest = max_zoom.unwrap_or(max(z, lowest));
+        let highest = min(wanted_highest, z.saturating_add(MAX_CHILD_ZOOMS));
+        if lowest > highest {
+            if ! warned_skipped {
+                println!("Warning: skipping zoom {} tiles, they are more than {} zooms below the min zoom {}", z, MAX_CHILD_ZOOMS, lowest);
+                warned_skipped = true;
+            }
+            continue;
+        }
+        if highest < wanted_highest && ! warned {
+            println!("Warning: only expiring the children of zoom {} tiles up to zoom {}, not {}", z, highest, wanted_highest);
+            warned = true;
+        }
+
+        for zoom in lowest..highest+1 {
+            if zoom <= z {
+                // Parent (or this tile)
+                let shift = z - zoom;
+                let parent = (zoom, x >> shift, y >> shift);
+                if seen.insert(parent) {
+                    result.push(parent);
+                }
+            } else {
+                // All the children on this zoom
+                let shift = zoom - z;
+                let (first_x, first_y) = (x << shift, y << shift);
+                for child_x in first_x..first_x+(1 << shift) {
+                    for child_y in first_y..first_y+(1 << shift) {
+                        let child = (zoom, child_x, child_y);
+                        if seen.insert(child) {
+                            result.push(child);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    result
+}
+
+fn get_expire_filenames(expire_directory: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    let entries = try!(expire_directory.read_dir());
     let entries = entries.filter_map(|entry| { entry.ok() });
     Ok(entries.filter(|entry| {
         let is_file = match entry.file_type().map(|f| f.is_file()) {
@@ -61,77 +260,516 @@ fn get_expire_filenames(expire_directory: &Path) -> Result<Vec<PathBuf>, ()> {
     }).map(|entry| { entry.path() }).collect::<Vec<_>>())
 }
 
-fn single_expire_run(filename_path: &PathBuf, pool: &mut simple_parallel::Pool, tc_path: &str, upstream_url: &str) -> Result<(), String> {
+/// Read the tiles to expire from one expire file (with zoom propagation), and the mtime of that
+/// file
+#[allow(deprecated)]
+fn read_expire_file(filename_path: &Path, settings: &ExpireSettings) -> Result<(Vec<(u8, u32, u32)>, time_t), String> {
     let filename = try!(try!(filename_path.file_name().ok_or("Couldn't get filename".to_string())).to_str().ok_or("Couldn't convert to string".to_string()));
     let file = try!(fs::File::open(&filename_path).map_err(|_| "Couldnt' open file".to_string()));
     let lines: Vec<_> = BufReader::new(file).lines().filter_map(|l| { l.ok() }).collect();
-    println!("Processing {:?} which has {} lines", filename, lines.len());
+    println!("Reading {:?} which has {} lines", filename, lines.len());
 
-    // Could use a .filter_map to get the from_tms, but we presume all the input is OK. Using a
-    // map ensures that we have accurate sizes which means the iter-progress can give us
-    // percentage views
-    let tiles = lines.iter().map(|l| { Tile::from_tms(l.as_str()).unwrap() });
+    let tiles = propagate_zooms(&parse_expire_lines(&lines, filename), settings.min_zoom, settings.max_zoom);
+    if settings.min_zoom.is_some() || settings.max_zoom.is_some() {
+        println!("{} tiles to expire after zoom propagation", tiles.len());
+    }
 
     let expiry_mtime = try!(filename_path.metadata().map_err(|_| "Couldn't get metadata".to_string())).mtime();
+    Ok((tiles, expiry_mtime))
+}
+
+/// When there are several expire files, the same tile is often in many of them. This merges them
+/// so that each tile is only expired once, with the newest mtime of the files it is in. Tiles are
+/// in the order they first appear.
+#[allow(deprecated)]
+fn merge_expired_tiles(files: &[(Vec<(u8, u32, u32)>, time_t)]) -> Vec<((u8, u32, u32), time_t)> {
+    let mut index: HashMap<(u8, u32, u32), usize> = HashMap::new();
+    let mut result: Vec<((u8, u32, u32), time_t)> = Vec::new();
+
+    for &(ref tiles, mtime) in files {
+        for &tile in tiles {
+            if let Some(&i) = index.get(&tile) {
+                result[i].1 = max(result[i].1, mtime);
+                continue;
+            }
+            index.insert(tile, result.len());
+            result.push((tile, mtime));
+        }
+    }
+
+    result
+}
+
+/// Expire all the tiles in these expire files (each tile only once), and then rename the files
+/// to `done-*`. Returns the files which were processed, and the ones which couldn't be (and why)
+fn expire_run(filename_paths: &[PathBuf], pool: &mut simple_parallel::Pool, source: &ExpireSource, settings: &ExpireSettings, http: &HttpOptions) -> Result<(Vec<PathBuf>, Vec<(PathBuf, String)>), String> {
+    let mut errors = Vec::new();
+    let mut processed = Vec::new();
+    let mut files = Vec::with_capacity(filename_paths.len());
+    let mut read_filename_paths = Vec::with_capacity(filename_paths.len());
+    for filename_path in filename_paths {
+        match read_expire_file(filename_path, settings) {
+            Ok(file) => {
+                files.push(file);
+                read_filename_paths.push(filename_path);
+            },
+            Err(e) => { errors.push((filename_path.clone(), e)); },
+        }
+    }
 
-    pool.for_(tiles.progress(), |(state, tile)| {
+    // Collecting into a Vec ensures that we have accurate sizes which means the iter-progress can
+    // give us percentage views
+    let total_tiles: usize = files.iter().map(|&(ref tiles, _)| tiles.len()).sum();
+    let tiles: Vec<(Tile, _)> = merge_expired_tiles(&files).into_iter().filter_map(|((z, x, y), mtime)| Tile::new(z, x, y).map(|t| (t, mtime))).collect();
+    println!("{} tiles to expire from {} files ({} duplicates skipped)", tiles.len(), files.len(), total_tiles - tiles.len());
+
+    let stats = try!(RefreshStats::new(settings.changed_tiles_list.as_ref().map(|s| s.as_str()), source.prefix.as_ref().map(|s| s.as_str())).map_err(|e| format!("Couldn't open the changed tiles list: {:?}", e)));
+
+    pool.for_(tiles.into_iter().progress(), |(state, (tile, expiry_mtime))| {
         state.print_every_n_items(100, format!("{:.0}% done ({:.1}/sec), tile {:?}       \r", state.percent().map(|x| x.to_string()).unwrap_or("N/A".to_string()), state.rate(), tile));
-        dl_tile_if_older(tile, &tc_path, &upstream_url, expiry_mtime);
+        dl_tile_if_older(tile, source, settings.mode, settings.path_format, expiry_mtime, http, &stats);
     });
-    let parent_dir = try!(filename_path.parent().ok_or("Directory".to_string()));
-    let new_filename = &parent_dir.join(format!("done-{}", filename));
+    println!("\n{}", stats);
+
+    for filename_path in read_filename_paths {
+        let result = filename_path.file_name().and_then(|f| f.to_str()).ok_or("Couldn't get filename".to_string()).and_then(|filename| {
+            let new_filename = filename_path.with_file_name(format!("done-{}", filename));
+            fs::rename(&filename_path, new_filename).map_err(|_| "Couldn't rename".to_string())
+        });
+        match result {
+            Ok(_) => {
+                println!("Finished processing file {:?}", filename_path);
+                processed.push(filename_path.clone());
+            },
+            Err(e) => { errors.push((filename_path.clone(), e)); },
+        }
+    }
 
+    Ok((processed, errors))
+}
 
-    fs::rename(&filename_path, new_filename).map_err(|_| "Couldn't rename".to_string())
+/// Is this the filename of an expire file which has been processed? Compressed files are only
+/// included if `include_compressed`
+fn is_done_filename(file_name: &str, include_compressed: bool) -> bool {
+    file_name.starts_with("done-expire-") && (file_name.ends_with(".txt") || (include_compressed && file_name.ends_with(".txt.gz")))
+}
+
+/// gzip this file (to `$FILE.gz`), and remove the original
+fn compress_file(path: &Path) -> io::Result<()> {
+    let mut compressed_path = path.as_os_str().to_owned();
+    compressed_path.push(".gz");
+    let mut encoder = try!(Encoder::new(try!(fs::File::create(&compressed_path))));
+    try!(io::copy(&mut try!(fs::File::open(path)), &mut encoder));
+    try!(encoder.finish().into_result());
+    fs::remove_file(path)
+}
+
+/// Move this file into this directory
+fn move_file(path: &Path, directory: &Path) -> io::Result<()> {
+    try!(fs::create_dir_all(directory));
+    let new_path = directory.join(try!(path.file_name().ok_or(io::Error::new(io::ErrorKind::Other, "No filename"))));
+    if fs::rename(path, &new_path).is_err() {
+        // Might be on a different filesystem
+        try!(fs::copy(path, &new_path));
+        try!(fs::remove_file(path));
+    }
+    Ok(())
+}
+
+/// Delete, compress or archive the processed expire files in this directory which are older than
+/// the retention period. Returns how many files were dealt with.
+fn apply_retention(source: &ExpireSource, retention: &Retention) -> Result<usize, io::Error> {
+    let include_compressed = retention.action != DoneAction::Compress;
+    let now = SystemTime::now();
+    let mut num_files = 0;
+
+    for entry in try!(source.expire_directory.read_dir()).filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let is_done_file = path.file_name().and_then(|f| f.to_str()).map(|f| is_done_filename(f, include_compressed)).unwrap_or(false);
+        if ! is_done_file || ! entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
+            continue;
+        }
+        let age = match entry.metadata().and_then(|m| m.modified()).map(|mtime| now.duration_since(mtime)) {
+            Ok(Ok(age)) => age,
+            _ => { continue; },
+        };
+        if age < retention.max_age {
+            continue;
+        }
+
+        let result = match retention.action {
+            DoneAction::Delete => fs::remove_file(&path),
+            DoneAction::Compress => compress_file(&path),
+            DoneAction::Archive(ref archive_dir) => {
+                match source.prefix {
+                    None => move_file(&path, archive_dir),
+                    Some(ref prefix) => move_file(&path, &archive_dir.join(prefix)),
+                }
+            },
+        };
+        match result {
+            Ok(_) => { num_files += 1; },
+            Err(e) => { println!("Couldn't {:?} old expire file {:?}: {}", retention.action, path, e); },
+        }
+    }
+
+    Ok(num_files)
+}
+
+/// Construct the ExpireSources from the command line options. Either one `--upstream` &
+/// `--expire-path`, or many `--prefix PREFIX URL EXPIRE_PATH`
+fn parse_out_sources(options: &ArgMatches, path: &str) -> Vec<ExpireSource> {
+    let new_upstream = |url: &str| {
+        let mut upstream = Upstream::new(url, None);
+        upstream.add_headers_from_options(options);
+        upstream
+    };
+
+    match options.values_of("prefix") {
+        None => {
+            vec![ExpireSource{
+                prefix: None,
+                tile_path: path.to_string(),
+                upstream: new_upstream(options.value_of("upstream_url").unwrap()),
+                expire_directory: PathBuf::from(options.value_of("expire_path").unwrap()),
+                consecutive_errors: 0,
+                retry_at: None,
+                failed_files: HashMap::new(),
+            }]
+        },
+        Some(values) => {
+            let values: Vec<_> = values.collect();
+            // The headers are sent to every upstream, so one host's secrets mustn't go to another
+            let hosts: HashSet<String> = values.chunks(3).map(|v| url_host(v[1]).to_lowercase()).collect();
+            let has_headers = ["upstream_header", "upstream_basic_auth", "upstream_bearer_token"].iter().any(|o| options.is_present(o));
+            if hosts.len() > 1 && has_headers {
+                println!("--upstream-header, --upstream-basic-auth & --upstream-bearer-token are sent to every --prefix upstream, so they can only be used if all the upstreams are on the same host");
+                ::std::process::exit(1);
+            }
+            values.chunks(3).map(|v| {
+                ExpireSource{
+                    prefix: Some(v[0].to_string()),
+                    tile_path: format!("{}/{}", path, v[0]),
+                    upstream: new_upstream(v[1]),
+                    expire_directory: PathBuf::from(v[2]),
+                    consecutive_errors: 0,
+                    retry_at: None,
+                    failed_files: HashMap::new(),
+                }
+            }).collect()
+        },
+    }
 }
 
 pub fn expire(options: &ArgMatches) {
 
-    let upstream_url = options.value_of("upstream_url").unwrap().to_string();
-    let tc_path = options.value_of("tc_path").unwrap().to_string();
+    let path = options.value_of("tc_path").or(options.value_of("ts_path")).or(options.value_of("zxy_path")).unwrap().to_string();
+    let path_format = if options.is_present("tc_path") { DirectoryLayout::TCPath } else if options.is_present("ts_path") { DirectoryLayout::TSPath } else if options.is_present("zxy_path") { DirectoryLayout::ZXYPath } else { unreachable!() };
     let threads = options.value_of("threads").unwrap().parse().unwrap();
 
-    let expire_path = options.value_of("expire_path").unwrap().to_string();
+    let mut sources = parse_out_sources(options, &path);
 
-    let wait_between_runs = options.value_of("wait_between_runs").unwrap().parse().unwrap();
+    let wait_between_runs = Duration::new(options.value_of("wait_between_runs").unwrap().parse().unwrap(), 0);
+
+    let http = HttpOptions::from_options(options);
+    let parse_zoom = |option: &str, flag: &str| -> Option<u8> {
+        options.value_of(option).map(|z| match z.parse() {
+            Ok(z) if z <= MAX_ZOOM => z,
+            _ => {
+                println!("Invalid {} {:?}, it must be between 0 and {}", flag, z, MAX_ZOOM);
+                ::std::process::exit(1);
+            },
+        })
+    };
+    let min_zoom = parse_zoom("expire_min_zoom", "--expire-min-zoom");
+    let max_zoom = parse_zoom("expire_max_zoom", "--expire-max-zoom");
+    if let (Some(min_zoom), Some(max_zoom)) = (min_zoom, max_zoom) {
+        if min_zoom > max_zoom {
+            println!("--expire-min-zoom ({}) is greater than --expire-max-zoom ({})", min_zoom, max_zoom);
+            ::std::process::exit(1);
+        }
+    }
+    let mode = ExpireMode::parse(options.value_of("mode").unwrap()).unwrap();
+    let retention = options.value_of("done_max_age").map(|days| {
+        let days: u64 = match days.parse() {
+            Ok(d) => d,
+            Err(_) => {
+                println!("Invalid --done-max-age {:?}", days);
+                ::std::process::exit(1);
+            },
+        };
+        let action = match options.value_of("done_action").unwrap() {
+            "delete" => DoneAction::Delete,
+            "compress" => DoneAction::Compress,
+            "archive" => DoneAction::Archive(PathBuf::from(options.value_of("archive_dir").unwrap())),
+            _ => unreachable!(),
+        };
+        Retention{ max_age: Duration::new(days * 24 * 60 * 60, 0), action: action }
+    });
+    let once = options.is_present("once");
+    let settings = ExpireSettings{
+        mode: mode,
+        path_format: path_format,
+        changed_tiles_list: options.value_of("changed_tiles_list").map(|s| s.to_string()),
+        min_zoom: min_zoom,
+        max_zoom: max_zoom,
+        retention: retention,
+    };
 
 
     println!("Starting {} threads", threads);
     let mut pool = simple_parallel::Pool::new(threads);
 
-    let expire_directory = Path::new(&expire_path);
+    let once_started = Instant::now();
+    let mut watcher = if once {
+        // Only looking once, so no need to watch for new files
+        DirectoryWatcher::polling()
+    } else {
+        let directories: Vec<&Path> = sources.iter().map(|s| s.expire_directory.as_path()).collect();
+        DirectoryWatcher::new(&directories)
+    };
+
+    // With --once, errors from any pass mean a non-zero exit code
+    let mut had_errors = false;
+    loop {
+        let mut processed_any = false;
+        let mut waiting_for_files = false;
+        // Find out about files which started being written while we were busy
+        watcher.poll();
+
+        // Each source is processed independently
+        for source in sources.iter_mut() {
+            if let Some(retry_at) = source.retry_at {
+                if Instant::now() < retry_at {
+                    continue;
+                }
+            }
 
+            let expire_filenames = match get_expire_filenames(&source.expire_directory) {
+                Ok(e) => e,
+                Err(e) => {
+                    had_errors = true;
+                    if once {
+                        println!("Couldn't read the expire directory {:?}: {}", source.expire_directory, e);
+                        continue;
+                    }
+                    // Something when wrong trying to get the files, so don't try again for a while
+                    source.consecutive_errors += 1;
+                    let wait = backoff(wait_between_runs, source.consecutive_errors);
+                    println!("Couldn't read the expire directory {:?}: {}. Trying again in {} sec", source.expire_directory, e, wait.as_secs());
+                    source.retry_at = Some(Instant::now() + wait);
+                    continue;
+                },
+            };
+            source.consecutive_errors = 0;
+            source.retry_at = None;
+
+            if let Some(ref retention) = settings.retention {
+                match apply_retention(source, retention) {
+                    Ok(0) => {},
+                    Ok(num_files) => { println!("{:?}: {} old processed expire files dealt with ({:?})", source.expire_directory, num_files, retention.action); },
+                    Err(e) => { println!("Couldn't look for old processed expire files in {:?}: {}", source.expire_directory, e); },
+                }
+            }
 
+            // Files which failed recently are tried again later, so their tiles aren't downloaded
+            // over & over
+            let now = Instant::now();
+            let expire_filenames: Vec<PathBuf> = expire_filenames.into_iter().filter(|f| source.failed_files.get(f).map_or(true, |&(_, retry_at)| now >= retry_at)).collect();
 
-    loop {
+            // Files which are still being written to will be processed later
+            let (expire_filenames, not_ready): (Vec<PathBuf>, Vec<PathBuf>) = expire_filenames.into_iter().partition(|f| watcher.is_ready(f));
+            if ! not_ready.is_empty() {
+                println!("Skipping {} files ({:?}) which are still being written, they will be processed once they are finished", not_ready.len(), not_ready);
+                waiting_for_files = true;
+            }
 
-        let expire_filenames = match get_expire_filenames(expire_directory) {
-            Ok(e) => e,
-            Err(_) => {
-                // Something when wrong trying to get the files
+            if expire_filenames.len() == 0 {
                 continue;
-            },
-        };
-
-        if expire_filenames.len() == 0 {
-            // Nothing to do, sleeping
-            sleep(Duration::new(wait_between_runs, 0));
-            continue;
-        }
+            }
 
-        println!("Found {} files ({:?}) to process", expire_filenames.len(), expire_filenames);
+            match source.prefix {
+                None => println!("Found {} files ({:?}) to process", expire_filenames.len(), expire_filenames),
+                Some(ref prefix) => println!("Found {} files ({:?}) to process for prefix {}", expire_filenames.len(), expire_filenames, prefix),
+            }
 
-        for filename_path in expire_filenames {
-            match single_expire_run(&filename_path, &mut pool, &tc_path, &upstream_url) {
-                Ok(_) => {
-                    println!("\nFinished processing file {:?}", filename_path);
+            // All the pending files are done together, so that tiles in many of them are only
+            // downloaded once
+            match expire_run(&expire_filenames, &mut pool, &*source, &settings, &http) {
+                Ok((processed, failed)) => {
+                    processed_any = processed_any || ! processed.is_empty();
+                    for file in processed {
+                        source.failed_files.remove(&file);
+                    }
+                    for (file, e) in failed {
+                        had_errors = true;
+                        let consecutive_errors = source.failed_files.get(&file).map_or(0, |&(n, _)| n) + 1;
+                        let wait = backoff(wait_between_runs, consecutive_errors);
+                        println!("Error when processing expire file {:?}: {}. Trying it again in {} sec", file, e, wait.as_secs());
+                        source.failed_files.insert(file, (consecutive_errors, Instant::now() + wait));
+                    }
                 },
                 Err(e) => {
-                    println!("\nCouldn't download {:?} error: {:?}", filename_path, e);
+                    had_errors = true;
+                    println!("\nError when processing expire files: {}", e);
                 },
             }
         }
+
+        if once {
+            if waiting_for_files {
+                if once_started.elapsed() < Duration::new(ONCE_MAX_SETTLE_SECS, 0) {
+                    // Don't exit without processing them
+                    watcher.wait(Duration::new(1, 0));
+                    continue;
+                }
+                println!("Gave up waiting for files which are still being written, they will be processed next time");
+            }
+            if had_errors {
+                ::std::process::exit(1);
+            }
+            break;
+        }
+
+        if ! processed_any {
+            // Nothing to do, wait for new files
+            watcher.wait(wait_between_runs);
+        }
     }
 
 }
 
+mod test {
+    #[test]
+    fn test_parse_expire_line() {
+        use super::parse_expire_line;
+
+        assert_eq!(parse_expire_line("14/8529/5468"), Ok(Some((14, 8529, 5468))));
+        assert_eq!(parse_expire_line("  14/8529/5468\r"), Ok(Some((14, 8529, 5468))));
+        assert_eq!(parse_expire_line("/14/8529/5468.pbf"), Ok(Some((14, 8529, 5468))));
+        assert_eq!(parse_expire_line("0/0/0"), Ok(Some((0, 0, 0))));
+        assert_eq!(parse_expire_line(""), Ok(None));
+        assert_eq!(parse_expire_line("# comment"), Ok(None));
+        assert!(parse_expire_line("14/8529").is_err());
+        assert!(parse_expire_line("14/8529/5468/1").is_err());
+        assert!(parse_expire_line("a/b/c").is_err());
+        assert!(parse_expire_line("1/2/0").is_err());
+        assert!(parse_expire_line("31/0/0").is_err());
+        assert!(parse_expire_line("14/-1/0").is_err());
+    }
+
+    #[test]
+    fn test_propagate_zooms() {
+        use super::propagate_zooms;
+
+        let tiles = vec![(2, 1, 1), (2, 1, 1), (2, 1, 0)];
+        assert_eq!(propagate_zooms(&tiles, None, None), vec![(2, 1, 1), (2, 1, 0)]);
+        assert_eq!(propagate_zooms(&tiles, Some(0), None), vec![(0, 0, 0), (1, 0, 0), (2, 1, 1), (2, 1, 0)]);
+        assert_eq!(propagate_zooms(&tiles, None, Some(3)), vec![
+            (2, 1, 1), (3, 2, 2), (3, 2, 3), (3, 3, 2), (3, 3, 3),
+            (2, 1, 0), (3, 2, 0), (3, 2, 1), (3, 3, 0), (3, 3, 1),
+        ]);
+        assert_eq!(propagate_zooms(&tiles, Some(1), Some(1)), vec![(1, 0, 0)]);
+        assert_eq!(propagate_zooms(&tiles, None, Some(1)), vec![(1, 0, 0)]);
+        assert_eq!(propagate_zooms(&tiles, Some(3), None).len(), 8);
+
+        // Children are only added for a few zooms
+        assert_eq!(propagate_zooms(&[(2, 1, 1)], None, Some(30)).iter().map(|t| t.0).max(), Some(10));
+        assert_eq!(propagate_zooms(&[(0, 0, 0)], Some(8), Some(30)).len(), 4*4*4*4*4*4*4*4);
+        // Even when the min zoom is higher, those tiles are skipped rather than expanded
+        assert_eq!(propagate_zooms(&[(0, 0, 0)], Some(15), None), vec![]);
+        assert_eq!(propagate_zooms(&[(0, 0, 0), (14, 1, 1)], Some(15), Some(15)), vec![(15, 2, 2), (15, 2, 3), (15, 3, 2), (15, 3, 3)]);
+        assert_eq!(propagate_zooms(&[(30, 5, 5)], None, Some(30)), vec![(30, 5, 5)]);
+    }
+
+    #[test]
+    fn test_merge_expired_tiles() {
+        use super::merge_expired_tiles;
+
+        let files = vec![
+            (vec![(1, 0, 0), (1, 1, 0)], 100),
+            (vec![(1, 1, 0), (1, 1, 1)], 200),
+            (vec![(1, 0, 0)], 50),
+        ];
+        assert_eq!(merge_expired_tiles(&files), vec![((1, 0, 0), 100), ((1, 1, 0), 200), ((1, 1, 1), 200)]);
+        assert_eq!(merge_expired_tiles(&[]), vec![]);
+    }
+
+    #[test]
+    fn test_apply_retention() {
+        use super::{apply_retention, ExpireSource, Retention, DoneAction};
+        use upstream::Upstream;
+        use filetime::{FileTime, set_file_times};
+        use std::collections::HashMap;
+        use std::fs;
+        use std::env;
+        use std::time::Duration;
+
+        let dir = env::temp_dir().join(format!("iompair-test-retention-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = ExpireSource{ prefix: Some("streets".to_string()), tile_path: String::new(), upstream: Upstream::new("http://example.com/", None),
+                                   expire_directory: dir.join("expire"), consecutive_errors: 0, retry_at: None, failed_files: HashMap::new() };
+        fs::create_dir_all(&source.expire_directory).unwrap();
+
+        let old = source.expire_directory.join("done-expire-1.txt");
+        let new = source.expire_directory.join("done-expire-2.txt");
+        let pending = source.expire_directory.join("expire-3.txt");
+        for path in &[&old, &new, &pending] {
+            fs::File::create(path).unwrap();
+        }
+        let long_ago = FileTime::from_seconds_since_1970(1_000_000_000, 0);
+        set_file_times(&old, long_ago, long_ago).unwrap();
+        set_file_times(&pending, long_ago, long_ago).unwrap();
+
+        let retention = Retention{ max_age: Duration::new(7 * 24 * 60 * 60, 0), action: DoneAction::Compress };
+        assert_eq!(apply_retention(&source, &retention).unwrap(), 1);
+        assert!(! old.exists());
+        assert!(source.expire_directory.join("done-expire-1.txt.gz").exists());
+        assert!(new.exists());
+        assert!(pending.exists());
+
+        // Compressed files are old too (the mtime is now), but aren't compressed again
+        assert_eq!(apply_retention(&source, &Retention{ max_age: Duration::new(0, 0), action: DoneAction::Compress }).unwrap(), 1);
+        assert!(source.expire_directory.join("done-expire-2.txt.gz").exists());
+
+        let retention = Retention{ max_age: Duration::new(0, 0), action: DoneAction::Archive(dir.join("archive")) };
+        assert_eq!(apply_retention(&source, &retention).unwrap(), 2);
+        assert!(dir.join("archive/streets/done-expire-1.txt.gz").exists());
+        assert!(pending.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_expire_run_failed_files() {
+        use super::{expire_run, ExpireSource, ExpireSettings, ExpireMode};
+        use upstream::Upstream;
+        use utils::{HttpOptions, DirectoryLayout};
+        use ratelimit::RateLimiter;
+        use proxy::ProxyConfig;
+        use simple_parallel;
+        use std::collections::HashMap;
+        use std::fs;
+        use std::env;
+
+        let dir = env::temp_dir().join(format!("iompair-test-expire-run-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = ExpireSource{ prefix: None, tile_path: dir.to_str().unwrap().to_string(), upstream: Upstream::new("http://example.com/", None),
+                                   expire_directory: dir.clone(), consecutive_errors: 0, retry_at: None, failed_files: HashMap::new() };
+        let settings = ExpireSettings{ mode: ExpireMode::Delete, path_format: DirectoryLayout::ZXYPath, changed_tiles_list: None, min_zoom: None, max_zoom: None, retention: None };
+        let http = HttpOptions{ limiter: RateLimiter::unlimited(), proxy: ProxyConfig::default() };
+        let mut pool = simple_parallel::Pool::new(1);
+
+        let good = dir.join("expire-1.txt");
+        let missing = dir.join("expire-2.txt");
+        fs::write(&good, "0/0/0\n").unwrap();
+
+        // Only the file which could be read & renamed counts as processed
+        let (processed, failed) = expire_run(&[good.clone(), missing.clone()], &mut pool, &source, &settings, &http).unwrap();
+        assert_eq!(processed, vec![good.clone()]);
+        assert_eq!(failed.iter().map(|f| f.0.clone()).collect::<Vec<_>>(), vec![missing]);
+        assert!(dir.join("done-expire-1.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
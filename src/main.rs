@@ -8,6 +8,8 @@ extern crate simple_parallel;
 extern crate iter_progress;
 extern crate chrono;
 extern crate libflate;
+extern crate filetime;
+extern crate inotify;
 
 use clap::{Arg, App, SubCommand, ArgGroup};
 
@@ -15,10 +17,18 @@ use clap::{Arg, App, SubCommand, ArgGroup};
 #[macro_use]
 mod utils;
 
+mod aliases;
+mod cors;
+mod ratelimit;
+mod proxy;
+mod upstream;
+mod watch;
 mod serve;
 mod stuffer;
 mod expire;
 mod tilelist;
+mod mvt;
+mod style;
 
 use serve::serve;
 use stuffer::stuffer;
@@ -38,6 +48,42 @@ fn main() {
             .arg(Arg::with_name("maxzoom").short("z").long("max-zoom")
                  .takes_value(true).default_value("14")
                  .help("Maximum zoom to pretend").value_name("ZOOM"))
+            .arg(Arg::with_name("overzoom_to").long("overzoom-to")
+                 .takes_value(true).required(false)
+                 .help("Serve tiles past --max-zoom, up to this zoom, by clipping & scaling the --max-zoom tile").value_name("ZOOM"))
+            .arg(Arg::with_name("aliases").long("aliases")
+                 .takes_value(true).required(false)
+                 .help("JSON file of aliases for lists of prefixes, e.g. {\"basemap\": [\"land\", \"points\"]}").value_name("FILE"))
+            .arg(Arg::with_name("no_concatenation").long("no-concatenation")
+                 .takes_value(false)
+                 .help("Don't allow joining prefixes with __ in URLs (aliases still work)"))
+            .arg(Arg::with_name("viewer").long("viewer")
+                 .takes_value(false)
+                 .help("Serve a map viewer page on /, for looking at the tiles"))
+            .arg(Arg::with_name("cors_origin").long("cors-origin")
+                 .takes_value(true).multiple(true).number_of_values(1)
+                 .help("Origin allowed to make cross-origin requests, e.g. https://example.com. Can be given more than once. (default: * for any)").value_name("ORIGIN"))
+            .arg(Arg::with_name("no_cors").long("no-cors")
+                 .takes_value(false).conflicts_with("cors_origin")
+                 .help("Don't send any CORS headers"))
+            .arg(Arg::with_name("cors_credentials").long("cors-credentials")
+                 .takes_value(false)
+                 .help("Allow cross-origin requests with credentials (cookies etc.). Needs --cors-origin"))
+            .arg(Arg::with_name("cors_expose_headers").long("cors-expose-headers")
+                 .takes_value(true).required(false)
+                 .help("Comma separated response headers that cross-origin clients can read, e.g. X-Cache from a caching proxy in front").value_name("HEADERS"))
+            .arg(Arg::with_name("cors_max_age").long("cors-max-age")
+                 .takes_value(true).default_value("86400")
+                 .help("How long clients can cache the answer to a preflight (OPTIONS) request").value_name("SECS"))
+            .arg(Arg::with_name("styles_dir").long("styles-dir")
+                 .takes_value(true).required(false)
+                 .help("Directory of style JSON files to serve under /styles/ (default: styles in the tile cache)").value_name("DIR"))
+            .arg(Arg::with_name("sprites_dir").long("sprites-dir")
+                 .takes_value(true).required(false)
+                 .help("Directory of sprites to serve under /sprites/ (default: sprites in the tile cache)").value_name("DIR"))
+            .arg(Arg::with_name("glyphs_dir").long("glyphs-dir")
+                 .takes_value(true).required(false)
+                 .help("Directory of glyph PBFs (FONT/START-END.pbf) to serve under /fonts/ (default: fonts in the tile cache)").value_name("DIR"))
             .arg(Arg::with_name("urlprefix").long("urlprefix")
                  .takes_value(true).required(false)
                  .help("URL that the tiles are accessible under").value_name("URL"))
@@ -56,17 +102,59 @@ fn main() {
             .group(ArgGroup::with_name("path").args(&["tc_path", "ts_path", "zxy_path"]).required(true))
             .arg(Arg::with_name("upstream_url").short("u").long("upstream")
                  .takes_value(true).multiple(true).number_of_values(2)
-                 .help("Local prefix & the URL of the upstream vector tiles producer(s)").value_name("PREFIX URL"))
+                 .help("Local prefix & the URL (or URL template) of the upstream vector tiles producer(s). Give the same prefix more than once for mirrors of the same tiles").value_name("PREFIX URL"))
+            .arg(Arg::with_name("upstream_tilejson_url").long("upstream-tilejson")
+                 .takes_value(true).multiple(true).number_of_values(2).requires("upstream_url")
+                 .help("Local prefix & the URL of the TileJSON for that upstream, if it's not $URL/index.json").value_name("PREFIX URL"))
+            .arg(Arg::with_name("upstream_header").long("upstream-header")
+                 .takes_value(true).multiple(true).number_of_values(2).requires("upstream_url")
+                 .help("Local prefix & a HTTP header ('Name: value') to send to that upstream. {env:VAR} & {file:PATH} in the value are replaced").value_name("PREFIX HEADER"))
+            .arg(Arg::with_name("upstream_basic_auth").long("upstream-basic-auth")
+                 .takes_value(true).multiple(true).number_of_values(2).requires("upstream_url")
+                 .help("Local prefix & the HTTP basic auth (user:password) for that upstream. {env:VAR} & {file:PATH} are replaced").value_name("PREFIX USER:PASSWORD"))
+            .arg(Arg::with_name("upstream_bearer_token").long("upstream-bearer-token")
+                 .takes_value(true).multiple(true).number_of_values(2).requires("upstream_url")
+                 .help("Local prefix & the bearer token for that upstream. {env:VAR} & {file:PATH} are replaced").value_name("PREFIX TOKEN"))
+            .arg(Arg::with_name("max_age").long("max-age")
+                 .takes_value(true).multiple(true).number_of_values(2).requires("upstream_url")
+                 .help("Local prefix & how long (in SEC) its cached tiles are fresh for. Older tiles are served straight away, and refreshed from the upstream in the background").value_name("PREFIX SEC"))
+            .arg(Arg::with_name("health_check_interval").long("health-check-interval")
+                 .takes_value(true).required(false).default_value("30")
+                 .help("How often (in SEC) to check whether upstreams which have failed are working again").value_name("SEC"))
+            .arg(Arg::with_name("max_background_refreshes").long("max-background-refreshes")
+                 .takes_value(true).required(false).default_value("10")
+                 .help("Maximum number of tiles which are refreshed in the background at once").value_name("NUM"))
             .arg(Arg::with_name("post-fetch-command").long("post-fetch-command")
                  .takes_value(true).required(false).requires("upstream_url")
                  .help("When a tile has been downloaded from upstream, execute this command on it").value_name("COMMAND"))
+            .arg(Arg::with_name("max-requests-per-sec").long("max-requests-per-sec")
+                 .takes_value(true).required(false)
+                 .help("Maximum number of requests per second to send to the upstream(s), shared between all threads").value_name("NUM"))
+            .arg(Arg::with_name("max-bandwidth").long("max-bandwidth")
+                 .takes_value(true).required(false)
+                 .help("Maximum number of bytes per second to download from the upstream(s), shared between all threads. Can use K, M, G suffixes").value_name("BYTES"))
+            .arg(Arg::with_name("proxy").long("proxy")
+                 .takes_value(true).required(false)
+                 .help("HTTP proxy to use for all upstream requests. Default: from the HTTP_PROXY/HTTPS_PROXY environment variables. NO_PROXY is respected").value_name("URL"))
             )
         .subcommand(SubCommand::with_name("stuffer")
             .about("Populate a tile cache directory with all the tiles in an area")
             .setting(clap::AppSettings::AllowLeadingHyphen)
             .arg(Arg::with_name("upstream_url").short("u").long("upstream")
                  .takes_value(true).required(true)
-                 .help("URL of the upstream vector tiles producer").value_name("URL"))
+                 .help("URL (or URL template) of the upstream vector tiles producer").value_name("URL"))
+            .arg(Arg::with_name("upstream_tilejson_url").long("upstream-tilejson")
+                 .takes_value(true).required(false)
+                 .help("URL of the upstream TileJSON, if it's not $URL/index.json").value_name("URL"))
+            .arg(Arg::with_name("upstream_header").long("upstream-header")
+                 .takes_value(true).multiple(true).number_of_values(1)
+                 .help("HTTP header ('Name: value') to send to the upstream. {env:VAR} & {file:PATH} in the value are replaced").value_name("HEADER"))
+            .arg(Arg::with_name("upstream_basic_auth").long("upstream-basic-auth")
+                 .takes_value(true).required(false)
+                 .help("HTTP basic auth (user:password) for the upstream. {env:VAR} & {file:PATH} are replaced").value_name("USER:PASSWORD"))
+            .arg(Arg::with_name("upstream_bearer_token").long("upstream-bearer-token")
+                 .takes_value(true).required(false)
+                 .help("Bearer token for the upstream. {env:VAR} & {file:PATH} are replaced").value_name("TOKEN"))
             .arg(Arg::with_name("tc_path").short("c").long("tc-path")
                  .takes_value(true)
                  .help("Directory to use as a tile cache.").value_name("PATH"))
@@ -77,43 +165,112 @@ fn main() {
                  .takes_value(true).required(false).default_value("4")
                  .help("Number of threads").value_name("THREADS"))
             .arg(Arg::with_name("max-zoom").short("z").long("max-zoom")
-                 .takes_value(true).required(false).default_value("14")
-                 .help("Maximum zoom to go to").value_name("ZOOM"))
+                 .takes_value(true).required(false)
+                 .help("Maximum zoom to go to. Default: maxzoom from the TileJSON, or 14").value_name("ZOOM"))
             .arg(Arg::with_name("min-zoom").long("min-zoom")
-                 .takes_value(true).required(false).default_value("0")
-                 .help("Minimum zoom to start from").value_name("ZOOM"))
+                 .takes_value(true).required(false)
+                 .help("Minimum zoom to start from. Default: minzoom from the TileJSON, or 0").value_name("ZOOM"))
             .arg(Arg::with_name("top").short("t").long("top")
-                 .takes_value(true).required(false).default_value("90"))
+                 .takes_value(true).required(false)
+                 .help("Top of the bbox. Default: from the TileJSON bounds, or 90"))
             .arg(Arg::with_name("left").short("l").long("left")
-                 .takes_value(true).required(false).default_value("-180"))
+                 .takes_value(true).required(false)
+                 .help("Left of the bbox. Default: from the TileJSON bounds, or -180"))
             .arg(Arg::with_name("bottom").short("b").long("bottom")
-                 .takes_value(true).required(false).default_value("-90"))
+                 .takes_value(true).required(false)
+                 .help("Bottom of the bbox. Default: from the TileJSON bounds, or -90"))
             .arg(Arg::with_name("right").short("r").long("right")
-                 .takes_value(true).required(false).default_value("180"))
+                 .takes_value(true).required(false)
+                 .help("Right of the bbox. Default: from the TileJSON bounds, or 180"))
             .arg(Arg::with_name("always-download").long("always-download")
                  .takes_value(false).required(false)
                  .help("Always download the files, even if they already exist"))
             .arg(Arg::with_name("files-older-than").long("files-older-than")
                  .takes_value(true).required(false)
                  .help("If using --always-download, only download a file that's missing or older than this RFC3339 datetime"))
+            .arg(Arg::with_name("changed_tiles_list").long("changed-tiles-list")
+                 .takes_value(true).required(false)
+                 .help("Append the Z/X/Y of every tile which was changed (new, or different contents) to this file").value_name("FILE"))
+            .arg(Arg::with_name("max-requests-per-sec").long("max-requests-per-sec")
+                 .takes_value(true).required(false)
+                 .help("Maximum number of requests per second to send to the upstream(s), shared between all threads").value_name("NUM"))
+            .arg(Arg::with_name("max-bandwidth").long("max-bandwidth")
+                 .takes_value(true).required(false)
+                 .help("Maximum number of bytes per second to download from the upstream(s), shared between all threads. Can use K, M, G suffixes").value_name("BYTES"))
+            .arg(Arg::with_name("proxy").long("proxy")
+                 .takes_value(true).required(false)
+                 .help("HTTP proxy to use for all upstream requests. Default: from the HTTP_PROXY/HTTPS_PROXY environment variables. NO_PROXY is respected").value_name("URL"))
             )
         .subcommand(SubCommand::with_name("expire")
             .about("Update a tilecache directory from upstream with osm2pgsql expiry tile list")
             .arg(Arg::with_name("upstream_url").short("u").long("upstream")
-                 .takes_value(true).required(true)
-                 .help("URL of the upstream vector tiles producer").value_name("URL"))
+                 .takes_value(true).required_unless("prefix").conflicts_with("prefix")
+                 .help("URL (or URL template) of the upstream vector tiles producer").value_name("URL"))
+            .arg(Arg::with_name("prefix").long("prefix")
+                 .takes_value(true).multiple(true).number_of_values(3)
+                 .help("A prefix (subdirectory of the tile cache), with the URL of its upstream, and the directory of its expire-*.txt files. Can be given many times").value_name("PREFIX URL EXPIRE_PATH"))
+            .arg(Arg::with_name("upstream_header").long("upstream-header")
+                 .takes_value(true).multiple(true).number_of_values(1)
+                 .help("HTTP header ('Name: value') to send to the upstream. {env:VAR} & {file:PATH} in the value are replaced").value_name("HEADER"))
+            .arg(Arg::with_name("upstream_basic_auth").long("upstream-basic-auth")
+                 .takes_value(true).required(false)
+                 .help("HTTP basic auth (user:password) for the upstream. {env:VAR} & {file:PATH} are replaced").value_name("USER:PASSWORD"))
+            .arg(Arg::with_name("upstream_bearer_token").long("upstream-bearer-token")
+                 .takes_value(true).required(false)
+                 .help("Bearer token for the upstream. {env:VAR} & {file:PATH} are replaced").value_name("TOKEN"))
             .arg(Arg::with_name("tc_path").short("c").long("tc-path")
-                 .takes_value(true).required(true)
+                 .takes_value(true)
                  .help("Directory to use as a tile cache.").value_name("PATH"))
+            .arg(Arg::with_name("ts_path").long("ts-path")
+                 .takes_value(true)
+                 .help("Directory to use as a tile cache (TileStash safe layout).").value_name("PATH"))
+            .arg(Arg::with_name("zxy_path").long("zxy-path")
+                 .takes_value(true)
+                 .help("Directory to use as a tile cache (ZXY layout).").value_name("PATH"))
+            .group(ArgGroup::with_name("path").args(&["tc_path", "ts_path", "zxy_path"]).required(true))
             .arg(Arg::with_name("threads").short("T").long("threads")
                  .takes_value(true).required(false).default_value("4")
                  .help("Number of threads").value_name("THREADS"))
             .arg(Arg::with_name("expire_path").short("e").long("expire-path")
-                 .takes_value(true).required(true)
+                 .takes_value(true).required_unless("prefix").conflicts_with("prefix")
                  .help("Directory which stores the expire-*.txt files").value_name("PATH"))
             .arg(Arg::with_name("wait_between_runs").short("w").long("wait")
                  .takes_value(true).required(false).default_value("60")
-                 .help("How long (in SEC) to wait between checks of the expire directory. Default 60 sec").value_name("SEC"))
+                 .help("How long (in SEC) to wait between checks of the expire directory. New files are noticed straight away if inotify can be used. Default 60 sec").value_name("SEC"))
+            .arg(Arg::with_name("expire_min_zoom").long("expire-min-zoom")
+                 .takes_value(true).required(false)
+                 .help("Also expire the parent tiles of every expired tile, down to this zoom").value_name("ZOOM"))
+            .arg(Arg::with_name("expire_max_zoom").long("expire-max-zoom")
+                 .takes_value(true).required(false)
+                 .help("Also expire the child tiles of every expired tile, up to this zoom").value_name("ZOOM"))
+            .arg(Arg::with_name("mode").long("mode")
+                 .takes_value(true).required(false).default_value("refresh")
+                 .possible_values(&["refresh", "delete", "mark-stale"])
+                 .help("What to do with expired tiles. refresh: download them again now. delete: delete them, so serve downloads them when next requested. mark-stale: serve sends the old tile when next requested, and refreshes it in the background").value_name("MODE"))
+            .arg(Arg::with_name("once").long("once")
+                 .help("Process the expire files which are there now, and then exit, rather than waiting for new files"))
+            .arg(Arg::with_name("done_max_age").long("done-max-age")
+                 .takes_value(true).required(false)
+                 .help("After a processed (done-*) expire file is this many DAYS old, apply the --done-action to it. By default they are kept forever").value_name("DAYS"))
+            .arg(Arg::with_name("done_action").long("done-action")
+                 .takes_value(true).required(false).default_value("delete")
+                 .possible_values(&["delete", "compress", "archive"])
+                 .help("What to do with old processed expire files. delete: delete them. compress: gzip them. archive: move them to --archive-dir").value_name("ACTION"))
+            .arg(Arg::with_name("archive_dir").long("archive-dir")
+                 .takes_value(true).required_if("done_action", "archive")
+                 .help("Directory to move old processed expire files to, with --done-action archive").value_name("DIR"))
+            .arg(Arg::with_name("changed_tiles_list").long("changed-tiles-list")
+                 .takes_value(true).required(false)
+                 .help("Append the Z/X/Y of every tile which was changed (new, or different contents) to this file").value_name("FILE"))
+            .arg(Arg::with_name("max-requests-per-sec").long("max-requests-per-sec")
+                 .takes_value(true).required(false)
+                 .help("Maximum number of requests per second to send to the upstream(s), shared between all threads").value_name("NUM"))
+            .arg(Arg::with_name("max-bandwidth").long("max-bandwidth")
+                 .takes_value(true).required(false)
+                 .help("Maximum number of bytes per second to download from the upstream(s), shared between all threads. Can use K, M, G suffixes").value_name("BYTES"))
+            .arg(Arg::with_name("proxy").long("proxy")
+                 .takes_value(true).required(false)
+                 .help("HTTP proxy to use for all upstream requests. Default: from the HTTP_PROXY/HTTPS_PROXY environment variables. NO_PROXY is respected").value_name("URL"))
             )
         .subcommand(SubCommand::with_name("tilelist")
             .about("Generate a Z/X/Y tile list (to stdout) based on tiles")
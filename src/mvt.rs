@@ -0,0 +1,973 @@
+//! A minimal reader & writer for Mapbox Vector Tiles (MVT), which are protobuf messages. See
+//! https://github.com/mapbox/vector-tile-spec/tree/master/2.1
+//!
+//! Only what iompair needs is here: decoding & encoding tiles, their geometries, clipping
+//! geometries for overzooming, and converting tiles to GeoJSON.
+extern crate rustc_serialize;
+
+use std::collections::{BTreeMap, HashMap};
+use std::f64::consts::PI;
+
+use rustc_serialize::json::Json;
+
+/// A decoded vector tile
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tile {
+    pub layers: Vec<Layer>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+    pub version: u32,
+    pub name: String,
+    pub extent: u32,
+    pub keys: Vec<String>,
+    pub values: Vec<Value>,
+    pub features: Vec<Feature>,
+}
+
+/// The value of a feature's property
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Float(f32),
+    Double(f64),
+    Int(i64),
+    UInt(u64),
+    SInt(i64),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeomType {
+    Unknown,
+    Point,
+    LineString,
+    Polygon,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Feature {
+    pub id: Option<u64>,
+    /// Pairs of indexes into the layer's keys & values
+    pub tags: Vec<u32>,
+    pub geom_type: GeomType,
+    /// The encoded geometry commands
+    pub geometry: Vec<u32>,
+}
+
+/// A decoded geometry, in tile coordinates. For points, there is one part with all the points.
+/// For linestrings, each part is a line. For polygons, each part is a ring (without the first
+/// point repeated at the end).
+pub type Geometry = Vec<Vec<(i64, i64)>>;
+
+const DEFAULT_EXTENT: u32 = 4096;
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_BYTES: u8 = 2;
+const WIRE_FIXED32: u8 = 5;
+
+const CMD_MOVE_TO: u32 = 1;
+const CMD_LINE_TO: u32 = 2;
+const CMD_CLOSE_PATH: u32 = 7;
+
+/// Reads protobuf fields from some bytes
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reader{ buf: buf, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn read_varint(&mut self) -> Result<u64, String> {
+        let mut result: u64 = 0;
+        let mut shift = 0;
+        loop {
+            if self.pos >= self.buf.len() {
+                return Err("Truncated varint".to_string());
+            }
+            if shift >= 64 {
+                return Err("Varint too long".to_string());
+            }
+            let byte = self.buf[self.pos];
+            self.pos += 1;
+            result |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+            shift += 7;
+        }
+    }
+
+    /// The field number & wire type of the next field
+    fn read_key(&mut self) -> Result<(u32, u8), String> {
+        let key = try!(self.read_varint());
+        Ok(((key >> 3) as u32, (key & 0x7) as u8))
+    }
+
+    fn read_fixed(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.pos + len > self.buf.len() {
+            return Err("Truncated field".to_string());
+        }
+        let bytes = &self.buf[self.pos..self.pos+len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn read_bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = try!(self.read_varint()) as usize;
+        self.read_fixed(len)
+    }
+
+    fn read_string(&mut self) -> Result<String, String> {
+        let bytes = try!(self.read_bytes());
+        String::from_utf8(bytes.to_vec()).map_err(|_| "Invalid UTF-8 string".to_string())
+    }
+
+    fn read_fixed32(&mut self) -> Result<u32, String> {
+        let b = try!(self.read_fixed(4));
+        Ok((b[0] as u32) | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24)
+    }
+
+    fn read_fixed64(&mut self) -> Result<u64, String> {
+        let low = try!(self.read_fixed32()) as u64;
+        let high = try!(self.read_fixed32()) as u64;
+        Ok(low | high << 32)
+    }
+
+    fn read_packed_varints(&mut self) -> Result<Vec<u32>, String> {
+        let mut packed = Reader::new(try!(self.read_bytes()));
+        let mut values = Vec::new();
+        while ! packed.is_empty() {
+            values.push(try!(packed.read_varint()) as u32);
+        }
+        Ok(values)
+    }
+
+    /// Skip over a field we don't care about
+    fn skip(&mut self, wire_type: u8) -> Result<(), String> {
+        match wire_type {
+            WIRE_VARINT => { try!(self.read_varint()); },
+            WIRE_FIXED64 => { try!(self.read_fixed(8)); },
+            WIRE_BYTES => { try!(self.read_bytes()); },
+            WIRE_FIXED32 => { try!(self.read_fixed(4)); },
+            _ => { return Err(format!("Unsupported wire type {}", wire_type)); },
+        }
+        Ok(())
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_key(out: &mut Vec<u8>, field: u32, wire_type: u8) {
+    write_varint(out, (field as u64) << 3 | wire_type as u64);
+}
+
+fn write_varint_field(out: &mut Vec<u8>, field: u32, value: u64) {
+    write_key(out, field, WIRE_VARINT);
+    write_varint(out, value);
+}
+
+fn write_bytes_field(out: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    write_key(out, field, WIRE_BYTES);
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn write_packed_field(out: &mut Vec<u8>, field: u32, values: &[u32]) {
+    let mut packed = Vec::with_capacity(values.len());
+    for &v in values {
+        write_varint(&mut packed, v as u64);
+    }
+    write_bytes_field(out, field, &packed);
+}
+
+fn zigzag_decode(n: u32) -> i64 {
+    ((n >> 1) as i64) ^ (-((n & 1) as i64))
+}
+
+fn zigzag_encode(n: i64) -> u32 {
+    ((n << 1) ^ (n >> 63)) as u32
+}
+
+impl Tile {
+    /// Decode an (uncompressed) vector tile. Several tiles concatenated together (like
+    /// `merge_vector_tiles` does) decode to one tile with all the layers.
+    pub fn decode(bytes: &[u8]) -> Result<Tile, String> {
+        let mut reader = Reader::new(bytes);
+        let mut layers = Vec::new();
+        while ! reader.is_empty() {
+            match try!(reader.read_key()) {
+                (3, WIRE_BYTES) => layers.push(try!(Layer::decode(try!(reader.read_bytes())))),
+                (_, wire_type) => try!(reader.skip(wire_type)),
+            }
+        }
+        Ok(Tile{ layers: layers })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for layer in self.layers.iter() {
+            write_bytes_field(&mut out, 3, &layer.encode());
+        }
+        out
+    }
+}
+
+/// Only keep the layers of this (uncompressed) tile which are named in `names`. The layers are
+/// copied as is, without decoding their features.
+pub fn filter_layers(bytes: &[u8], names: &[String]) -> Result<Vec<u8>, String> {
+    let mut reader = Reader::new(bytes);
+    let mut out = Vec::new();
+    while ! reader.is_empty() {
+        match try!(reader.read_key()) {
+            (3, WIRE_BYTES) => {
+                let layer = try!(reader.read_bytes());
+                if names.contains(&try!(layer_name(layer))) {
+                    write_bytes_field(&mut out, 3, layer);
+                }
+            },
+            (_, wire_type) => try!(reader.skip(wire_type)),
+        }
+    }
+    Ok(out)
+}
+
+/// Rename the layers of this (uncompressed) tile which are in `renames` (old name to new name)
+pub fn rename_layers(bytes: &[u8], renames: &HashMap<String, String>) -> Result<Vec<u8>, String> {
+    let mut reader = Reader::new(bytes);
+    let mut out = Vec::new();
+    while ! reader.is_empty() {
+        match try!(reader.read_key()) {
+            (3, WIRE_BYTES) => {
+                let layer_bytes = try!(reader.read_bytes());
+                match renames.get(&try!(layer_name(layer_bytes))) {
+                    None => write_bytes_field(&mut out, 3, layer_bytes),
+                    Some(new_name) => {
+                        let mut layer = try!(Layer::decode(layer_bytes));
+                        layer.name = new_name.clone();
+                        write_bytes_field(&mut out, 3, &layer.encode());
+                    },
+                }
+            },
+            (_, wire_type) => try!(reader.skip(wire_type)),
+        }
+    }
+    Ok(out)
+}
+
+/// The name of this encoded layer
+fn layer_name(bytes: &[u8]) -> Result<String, String> {
+    let mut reader = Reader::new(bytes);
+    while ! reader.is_empty() {
+        match try!(reader.read_key()) {
+            (1, WIRE_BYTES) => { return reader.read_string(); },
+            (_, wire_type) => try!(reader.skip(wire_type)),
+        }
+    }
+    Err("Layer has no name".to_string())
+}
+
+impl Layer {
+    fn decode(bytes: &[u8]) -> Result<Layer, String> {
+        let mut reader = Reader::new(bytes);
+        let mut layer = Layer{ version: 1, name: String::new(), extent: DEFAULT_EXTENT, keys: Vec::new(), values: Vec::new(), features: Vec::new() };
+        while ! reader.is_empty() {
+            match try!(reader.read_key()) {
+                (15, WIRE_VARINT) => layer.version = try!(reader.read_varint()) as u32,
+                (1, WIRE_BYTES) => layer.name = try!(reader.read_string()),
+                (2, WIRE_BYTES) => layer.features.push(try!(Feature::decode(try!(reader.read_bytes())))),
+                (3, WIRE_BYTES) => layer.keys.push(try!(reader.read_string())),
+                (4, WIRE_BYTES) => layer.values.push(try!(Value::decode(try!(reader.read_bytes())))),
+                (5, WIRE_VARINT) => layer.extent = try!(reader.read_varint()) as u32,
+                (_, wire_type) => try!(reader.skip(wire_type)),
+            }
+        }
+        if layer.extent == 0 {
+            return Err(format!("Layer {:?} has an extent of 0", layer.name));
+        }
+        Ok(layer)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_varint_field(&mut out, 15, self.version as u64);
+        write_bytes_field(&mut out, 1, self.name.as_bytes());
+        for feature in self.features.iter() {
+            write_bytes_field(&mut out, 2, &feature.encode());
+        }
+        for key in self.keys.iter() {
+            write_bytes_field(&mut out, 3, key.as_bytes());
+        }
+        for value in self.values.iter() {
+            write_bytes_field(&mut out, 4, &value.encode());
+        }
+        write_varint_field(&mut out, 5, self.extent as u64);
+        out
+    }
+
+    /// The properties of this feature, as (key, value) pairs. Invalid tags are ignored.
+    pub fn properties<'a>(&'a self, feature: &Feature) -> Vec<(&'a str, &'a Value)> {
+        feature.tags.chunks(2).filter(|t| t.len() == 2).filter_map(|t| {
+            match (self.keys.get(t[0] as usize), self.values.get(t[1] as usize)) {
+                (Some(k), Some(v)) => Some((k.as_str(), v)),
+                _ => None,
+            }
+        }).collect()
+    }
+}
+
+impl Value {
+    fn decode(bytes: &[u8]) -> Result<Value, String> {
+        let mut reader = Reader::new(bytes);
+        let mut value = None;
+        while ! reader.is_empty() {
+            value = Some(match try!(reader.read_key()) {
+                (1, WIRE_BYTES) => Value::String(try!(reader.read_string())),
+                (2, WIRE_FIXED32) => Value::Float(f32::from_bits(try!(reader.read_fixed32()))),
+                (3, WIRE_FIXED64) => Value::Double(f64::from_bits(try!(reader.read_fixed64()))),
+                (4, WIRE_VARINT) => Value::Int(try!(reader.read_varint()) as i64),
+                (5, WIRE_VARINT) => Value::UInt(try!(reader.read_varint())),
+                (6, WIRE_VARINT) => {
+                    let n = try!(reader.read_varint());
+                    Value::SInt(((n >> 1) as i64) ^ (-((n & 1) as i64)))
+                },
+                (7, WIRE_VARINT) => Value::Bool(try!(reader.read_varint()) != 0),
+                (_, wire_type) => { try!(reader.skip(wire_type)); continue; },
+            });
+        }
+        value.ok_or("Empty value".to_string())
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match *self {
+            Value::String(ref s) => write_bytes_field(&mut out, 1, s.as_bytes()),
+            Value::Float(f) => {
+                write_key(&mut out, 2, WIRE_FIXED32);
+                let bits = f.to_bits();
+                out.extend_from_slice(&[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8]);
+            },
+            Value::Double(d) => {
+                write_key(&mut out, 3, WIRE_FIXED64);
+                let bits = d.to_bits();
+                for i in 0..8 {
+                    out.push((bits >> (i * 8)) as u8);
+                }
+            },
+            Value::Int(i) => write_varint_field(&mut out, 4, i as u64),
+            Value::UInt(u) => write_varint_field(&mut out, 5, u),
+            Value::SInt(i) => write_varint_field(&mut out, 6, ((i << 1) ^ (i >> 63)) as u64),
+            Value::Bool(b) => write_varint_field(&mut out, 7, b as u64),
+        }
+        out
+    }
+}
+
+impl GeomType {
+    fn from_u64(n: u64) -> Self {
+        match n {
+            1 => GeomType::Point,
+            2 => GeomType::LineString,
+            3 => GeomType::Polygon,
+            _ => GeomType::Unknown,
+        }
+    }
+
+    fn to_u64(&self) -> u64 {
+        match *self {
+            GeomType::Unknown => 0,
+            GeomType::Point => 1,
+            GeomType::LineString => 2,
+            GeomType::Polygon => 3,
+        }
+    }
+}
+
+impl Feature {
+    fn decode(bytes: &[u8]) -> Result<Feature, String> {
+        let mut reader = Reader::new(bytes);
+        let mut feature = Feature{ id: None, tags: Vec::new(), geom_type: GeomType::Unknown, geometry: Vec::new() };
+        while ! reader.is_empty() {
+            match try!(reader.read_key()) {
+                (1, WIRE_VARINT) => feature.id = Some(try!(reader.read_varint())),
+                (2, WIRE_BYTES) => feature.tags = try!(reader.read_packed_varints()),
+                (3, WIRE_VARINT) => feature.geom_type = GeomType::from_u64(try!(reader.read_varint())),
+                (4, WIRE_BYTES) => feature.geometry = try!(reader.read_packed_varints()),
+                (_, wire_type) => try!(reader.skip(wire_type)),
+            }
+        }
+        Ok(feature)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        if let Some(id) = self.id {
+            write_varint_field(&mut out, 1, id);
+        }
+        if ! self.tags.is_empty() {
+            write_packed_field(&mut out, 2, &self.tags);
+        }
+        write_varint_field(&mut out, 3, self.geom_type.to_u64());
+        write_packed_field(&mut out, 4, &self.geometry);
+        out
+    }
+
+    /// Decode the geometry commands of this feature
+    pub fn decode_geometry(&self) -> Result<Geometry, String> {
+        decode_geometry(self.geom_type, &self.geometry)
+    }
+}
+
+fn decode_geometry(geom_type: GeomType, commands: &[u32]) -> Result<Geometry, String> {
+    let mut parts: Geometry = Vec::new();
+    let (mut x, mut y) = (0i64, 0i64);
+    let mut i = 0;
+    while i < commands.len() {
+        let command = commands[i] & 0x7;
+        let count = (commands[i] >> 3) as usize;
+        i += 1;
+        match command {
+            CMD_MOVE_TO | CMD_LINE_TO => {
+                if i + count * 2 > commands.len() {
+                    return Err("Truncated geometry".to_string());
+                }
+                for _ in 0..count {
+                    x += zigzag_decode(commands[i]);
+                    y += zigzag_decode(commands[i+1]);
+                    i += 2;
+                    if command == CMD_MOVE_TO && (geom_type != GeomType::Point || parts.is_empty()) {
+                        parts.push(Vec::new());
+                    } else if parts.is_empty() {
+                        return Err("LineTo before MoveTo".to_string());
+                    }
+                    parts.last_mut().unwrap().push((x, y));
+                }
+            },
+            CMD_CLOSE_PATH => {},
+            _ => { return Err(format!("Unknown geometry command {}", command)); },
+        }
+    }
+    Ok(parts)
+}
+
+fn encode_geometry(geom_type: GeomType, parts: &Geometry) -> Vec<u32> {
+    let mut commands = Vec::new();
+    let (mut cursor_x, mut cursor_y) = (0i64, 0i64);
+    {
+        let mut push_point = |commands: &mut Vec<u32>, (x, y): (i64, i64)| {
+            commands.push(zigzag_encode(x - cursor_x));
+            commands.push(zigzag_encode(y - cursor_y));
+            cursor_x = x;
+            cursor_y = y;
+        };
+
+        if geom_type == GeomType::Point {
+            let points: Vec<(i64, i64)> = parts.iter().flat_map(|p| p.iter().cloned()).collect();
+            if ! points.is_empty() {
+                commands.push(CMD_MOVE_TO | (points.len() as u32) << 3);
+                for point in points {
+                    push_point(&mut commands, point);
+                }
+            }
+            return commands;
+        }
+
+        for part in parts.iter().filter(|p| ! p.is_empty()) {
+            commands.push(CMD_MOVE_TO | 1 << 3);
+            push_point(&mut commands, part[0]);
+            if part.len() > 1 {
+                commands.push(CMD_LINE_TO | ((part.len() - 1) as u32) << 3);
+                for &point in part[1..].iter() {
+                    push_point(&mut commands, point);
+                }
+            }
+            if geom_type == GeomType::Polygon {
+                commands.push(CMD_CLOSE_PATH | 1 << 3);
+            }
+        }
+    }
+    commands
+}
+
+/// Twice the signed area of this ring. In tile coordinates (y down), exterior rings are
+/// positive and interior rings (holes) are negative. i128, since when overzooming the (not yet
+/// clipped) coordinates are scaled up by as much as 2^30, and the products overflow an i64.
+fn ring_area(ring: &[(i64, i64)]) -> i128 {
+    let mut area = 0;
+    for i in 0..ring.len() {
+        let (x1, y1) = (ring[i].0 as i128, ring[i].1 as i128);
+        let (x2, y2) = (ring[(i + 1) % ring.len()].0 as i128, ring[(i + 1) % ring.len()].1 as i128);
+        area += x1 * y2 - x2 * y1;
+    }
+    area
+}
+
+/// Remove points which are the same as the one before
+fn dedup_points(points: &mut Vec<(i64, i64)>) {
+    points.dedup();
+}
+
+/// Where along the line from `a` to `b` it crosses `value` on `axis` (0 for x, 1 for y)
+fn intersect(a: (i64, i64), b: (i64, i64), axis: usize, value: i64) -> (i64, i64) {
+    let (a, b) = ((a.0 as f64, a.1 as f64), (b.0 as f64, b.1 as f64));
+    if axis == 0 {
+        let t = (value as f64 - a.0) / (b.0 - a.0);
+        (value, (a.1 + t * (b.1 - a.1)).round() as i64)
+    } else {
+        let t = (value as f64 - a.1) / (b.1 - a.1);
+        ((a.0 + t * (b.0 - a.0)).round() as i64, value)
+    }
+}
+
+/// Clip a polygon ring to the square `min`..`max` (Sutherland-Hodgman). The winding order is
+/// kept. Returns an empty ring if nothing is left.
+fn clip_ring(ring: &[(i64, i64)], min: i64, max: i64) -> Vec<(i64, i64)> {
+    let mut output: Vec<(i64, i64)> = ring.to_vec();
+    // (axis, is this the min edge)
+    for &(axis, is_min) in [(0, true), (0, false), (1, true), (1, false)].iter() {
+        let edge = if is_min { min } else { max };
+        let inside = |p: (i64, i64)| {
+            let v = if axis == 0 { p.0 } else { p.1 };
+            if is_min { v >= edge } else { v <= edge }
+        };
+        let input = output;
+        output = Vec::with_capacity(input.len());
+        for i in 0..input.len() {
+            let current = input[i];
+            let previous = input[(i + input.len() - 1) % input.len()];
+            match (inside(previous), inside(current)) {
+                (true, true) => output.push(current),
+                (true, false) => output.push(intersect(previous, current, axis, edge)),
+                (false, true) => {
+                    output.push(intersect(previous, current, axis, edge));
+                    output.push(current);
+                },
+                (false, false) => {},
+            }
+        }
+    }
+    dedup_points(&mut output);
+    while output.len() > 1 && output.first() == output.last() {
+        output.pop();
+    }
+    if output.len() < 3 || ring_area(&output) == 0 {
+        output.clear();
+    }
+    output
+}
+
+/// Clip one segment to the square `min`..`max` (Liang-Barsky)
+fn clip_segment(a: (i64, i64), b: (i64, i64), min: i64, max: i64) -> Option<((i64, i64), (i64, i64))> {
+    let (dx, dy) = ((b.0 - a.0) as f64, (b.1 - a.1) as f64);
+    let (mut t0, mut t1) = (0f64, 1f64);
+    let checks = [(-dx, (a.0 - min) as f64), (dx, (max - a.0) as f64), (-dy, (a.1 - min) as f64), (dy, (max - a.1) as f64)];
+    for &(p, q) in checks.iter() {
+        if p == 0. {
+            if q < 0. {
+                return None;
+            }
+        } else {
+            let r = q / p;
+            if p < 0. {
+                if r > t1 { return None; }
+                if r > t0 { t0 = r; }
+            } else {
+                if r < t0 { return None; }
+                if r < t1 { t1 = r; }
+            }
+        }
+    }
+    let at = |t: f64| if t == 0. { a } else if t == 1. { b } else { ((a.0 as f64 + t * dx).round() as i64, (a.1 as f64 + t * dy).round() as i64) };
+    Some((at(t0), at(t1)))
+}
+
+/// Clip a line to the square `min`..`max`. It might be split into several lines.
+fn clip_line(line: &[(i64, i64)], min: i64, max: i64) -> Vec<Vec<(i64, i64)>> {
+    let mut lines = Vec::new();
+    let mut current: Vec<(i64, i64)> = Vec::new();
+    for segment in line.windows(2) {
+        match clip_segment(segment[0], segment[1], min, max) {
+            None => {
+                if current.len() > 1 { lines.push(current); }
+                current = Vec::new();
+            },
+            Some((start, end)) => {
+                if current.last() != Some(&start) {
+                    if current.len() > 1 { lines.push(current); }
+                    current = vec![start];
+                }
+                current.push(end);
+            },
+        }
+    }
+    if current.len() > 1 { lines.push(current); }
+
+    for line in lines.iter_mut() {
+        dedup_points(line);
+    }
+    lines.into_iter().filter(|l| l.len() > 1).collect()
+}
+
+/// Clip a geometry to the square `min`..`max`
+fn clip_geometry(geom_type: GeomType, geometry: Geometry, min: i64, max: i64) -> Geometry {
+    let in_box = |&(x, y): &(i64, i64)| x >= min && x <= max && y >= min && y <= max;
+    match geom_type {
+        GeomType::Point => {
+            let points: Vec<(i64, i64)> = geometry.into_iter().flat_map(|p| p.into_iter()).filter(|p| in_box(p)).collect();
+            if points.is_empty() { Vec::new() } else { vec![points] }
+        },
+        GeomType::LineString => geometry.iter().flat_map(|line| clip_line(line, min, max).into_iter()).collect(),
+        GeomType::Polygon => {
+            let mut rings = Vec::new();
+            // If an exterior ring is clipped away, so are its holes
+            let mut keep_holes = false;
+            for ring in geometry.iter() {
+                let is_exterior = ring_area(ring) > 0;
+                if ! is_exterior && ! keep_holes {
+                    continue;
+                }
+                let clipped = clip_ring(ring, min, max);
+                if is_exterior {
+                    keep_holes = ! clipped.is_empty();
+                }
+                if ! clipped.is_empty() {
+                    rings.push(clipped);
+                }
+            }
+            rings
+        },
+        GeomType::Unknown => Vec::new(),
+    }
+}
+
+/// Make a tile for a zoom `dz` levels deeper than `tile`, by scaling up & clipping `tile`'s
+/// geometries. (`x`, `y`) is which of the child tiles to make, relative to `tile`, i.e. from 0
+/// to 2^dz - 1. Features (and layers) which end up empty are removed.
+pub fn overzoom(tile: &Tile, dz: u8, x: u32, y: u32) -> Tile {
+    let scale = 1i64 << dz;
+    let layers = tile.layers.iter().filter_map(|layer| {
+        let extent = layer.extent as i64;
+        // Keep a little bit of geometry outside the tile, so lines & polygon edges don't show
+        let buffer = extent / 64;
+        let (offset_x, offset_y) = (x as i64 * extent, y as i64 * extent);
+
+        let features: Vec<Feature> = layer.features.iter().filter_map(|feature| {
+            let geometry = match feature.decode_geometry() {
+                Ok(g) => g,
+                Err(_) => { return None; },
+            };
+            let geometry: Geometry = geometry.into_iter().map(|part| {
+                let mut part: Vec<(i64, i64)> = part.into_iter().map(|(px, py)| (px * scale - offset_x, py * scale - offset_y)).collect();
+                dedup_points(&mut part);
+                part
+            }).collect();
+            let geometry = clip_geometry(feature.geom_type, geometry, -buffer, extent + buffer);
+            if geometry.is_empty() {
+                return None;
+            }
+            Some(Feature{ id: feature.id, tags: feature.tags.clone(), geom_type: feature.geom_type, geometry: encode_geometry(feature.geom_type, &geometry) })
+        }).collect();
+
+        if features.is_empty() {
+            None
+        } else {
+            Some(Layer{ version: layer.version, name: layer.name.clone(), extent: layer.extent, keys: layer.keys.clone(), values: layer.values.clone(), features: features })
+        }
+    }).collect();
+
+    Tile{ layers: layers }
+}
+
+impl Value {
+    fn to_json(&self) -> Json {
+        match *self {
+            Value::String(ref s) => Json::String(s.clone()),
+            Value::Float(f) => Json::F64(f as f64),
+            Value::Double(d) => Json::F64(d),
+            Value::Int(i) | Value::SInt(i) => Json::I64(i),
+            Value::UInt(u) => Json::U64(u),
+            Value::Bool(b) => Json::Boolean(b),
+        }
+    }
+}
+
+/// Convert a point in tile `z`/`x`/`y` (with this extent) to a [longitude, latitude] position
+fn to_lon_lat(z: u8, x: u32, y: u32, extent: u32, (px, py): (i64, i64)) -> Json {
+    let num_tiles = (1u64 << z) as f64;
+    let u = (x as f64 + px as f64 / extent as f64) / num_tiles;
+    let v = (y as f64 + py as f64 / extent as f64) / num_tiles;
+    let lon = u * 360. - 180.;
+    let lat = (PI * (1. - 2. * v)).sinh().atan().to_degrees();
+    Json::Array(vec![Json::F64(lon), Json::F64(lat)])
+}
+
+/// A GeoJSON geometry object, using the single type (e.g. `Polygon`) if there is only one part,
+/// else the multi type (e.g. `MultiPolygon`)
+fn geojson_geometry(single_type: &str, mut parts: Vec<Json>) -> Json {
+    let mut geometry = BTreeMap::new();
+    if parts.len() == 1 {
+        geometry.insert("type".to_string(), Json::String(single_type.to_string()));
+        geometry.insert("coordinates".to_string(), parts.remove(0));
+    } else {
+        geometry.insert("type".to_string(), Json::String(format!("Multi{}", single_type)));
+        geometry.insert("coordinates".to_string(), Json::Array(parts));
+    }
+    Json::Object(geometry)
+}
+
+/// Convert a decoded geometry, in tile `z`/`x`/`y`, to a GeoJSON geometry in WGS84. `None` if
+/// there is nothing to convert.
+fn feature_geometry_to_geojson(geom_type: GeomType, geometry: &Geometry, z: u8, x: u32, y: u32, extent: u32) -> Option<Json> {
+    let line = |points: &[(i64, i64)]| Json::Array(points.iter().map(|&p| to_lon_lat(z, x, y, extent, p)).collect());
+    match geom_type {
+        GeomType::Point => {
+            let points: Vec<Json> = geometry.iter().flat_map(|p| p.iter()).map(|&p| to_lon_lat(z, x, y, extent, p)).collect();
+            if points.is_empty() { None } else { Some(geojson_geometry("Point", points)) }
+        },
+        GeomType::LineString => {
+            let lines: Vec<Json> = geometry.iter().filter(|l| l.len() > 1).map(|l| line(l)).collect();
+            if lines.is_empty() { None } else { Some(geojson_geometry("LineString", lines)) }
+        },
+        GeomType::Polygon => {
+            // Each exterior ring starts a new polygon, and the holes after it belong to it. The
+            // y axis is flipped, so exterior rings become anticlockwise, like GeoJSON wants.
+            let mut polygons: Vec<Vec<Json>> = Vec::new();
+            for ring in geometry.iter().filter(|r| r.len() > 2) {
+                let area = ring_area(ring);
+                if area > 0 {
+                    polygons.push(Vec::new());
+                } else if area == 0 || polygons.is_empty() {
+                    continue;
+                }
+                let mut closed = ring.clone();
+                closed.push(ring[0]);
+                polygons.last_mut().unwrap().push(line(&closed));
+            }
+            if polygons.is_empty() { None } else { Some(geojson_geometry("Polygon", polygons.into_iter().map(Json::Array).collect())) }
+        },
+        GeomType::Unknown => None,
+    }
+}
+
+/// Convert this tile (for `z`/`x`/`y`) to a GeoJSON FeatureCollection, in WGS84. Each feature has
+/// a `layer` member with the name of the layer it's in. Features with invalid or unknown
+/// geometries are left out.
+pub fn to_geojson(tile: &Tile, z: u8, x: u32, y: u32) -> Json {
+    let mut features = Vec::new();
+    for layer in tile.layers.iter() {
+        for feature in layer.features.iter() {
+            let geometry = match feature.decode_geometry().ok().and_then(|g| feature_geometry_to_geojson(feature.geom_type, &g, z, x, y, layer.extent)) {
+                Some(g) => g,
+                None => { continue; },
+            };
+
+            let mut properties = BTreeMap::new();
+            for (key, value) in layer.properties(feature) {
+                properties.insert(key.to_string(), value.to_json());
+            }
+
+            let mut geojson_feature = BTreeMap::new();
+            geojson_feature.insert("type".to_string(), Json::String("Feature".to_string()));
+            // A foreign member, since features can have their own `layer` property
+            geojson_feature.insert("layer".to_string(), Json::String(layer.name.clone()));
+            if let Some(id) = feature.id {
+                geojson_feature.insert("id".to_string(), Json::U64(id));
+            }
+            geojson_feature.insert("properties".to_string(), Json::Object(properties));
+            geojson_feature.insert("geometry".to_string(), geometry);
+            features.push(Json::Object(geojson_feature));
+        }
+    }
+
+    let mut collection = BTreeMap::new();
+    collection.insert("type".to_string(), Json::String("FeatureCollection".to_string()));
+    collection.insert("features".to_string(), Json::Array(features));
+    Json::Object(collection)
+}
+
+mod test {
+    #[allow(unused)]
+    fn test_layer(geom_type: super::GeomType, geometry: super::Geometry) -> super::Layer {
+        use super::{Layer, Feature, Value, encode_geometry};
+        Layer{
+            version: 2, name: "test".to_string(), extent: 4096,
+            keys: vec!["name".to_string()], values: vec![Value::String("thing".to_string())],
+            features: vec![Feature{ id: Some(1), tags: vec![0, 0], geom_type: geom_type, geometry: encode_geometry(geom_type, &geometry) }],
+        }
+    }
+
+    #[test]
+    fn test_geometry_encoding() {
+        use super::{decode_geometry, encode_geometry, GeomType};
+
+        // Examples from the spec
+        assert_eq!(decode_geometry(GeomType::Point, &[9, 50, 34]), Ok(vec![vec![(25, 17)]]));
+        assert_eq!(decode_geometry(GeomType::Point, &[17, 10, 14, 3, 9]), Ok(vec![vec![(5, 7), (3, 2)]]));
+        assert_eq!(decode_geometry(GeomType::LineString, &[9, 4, 4, 18, 0, 16, 16, 0]), Ok(vec![vec![(2, 2), (2, 10), (10, 10)]]));
+        assert_eq!(decode_geometry(GeomType::Polygon, &[9, 6, 12, 18, 10, 12, 24, 44, 15]), Ok(vec![vec![(3, 6), (8, 12), (20, 34)]]));
+        assert!(decode_geometry(GeomType::LineString, &[9, 4]).is_err());
+        assert!(decode_geometry(GeomType::LineString, &[18, 0, 16]).is_err());
+
+        assert_eq!(encode_geometry(GeomType::Point, &vec![vec![(5, 7), (3, 2)]]), vec![17, 10, 14, 3, 9]);
+        assert_eq!(encode_geometry(GeomType::LineString, &vec![vec![(2, 2), (2, 10), (10, 10)]]), vec![9, 4, 4, 18, 0, 16, 16, 0]);
+        assert_eq!(encode_geometry(GeomType::Polygon, &vec![vec![(3, 6), (8, 12), (20, 34)]]), vec![9, 6, 12, 18, 10, 12, 24, 44, 15]);
+    }
+
+    #[test]
+    fn test_tile_roundtrip() {
+        use super::{Tile, GeomType, Value, filter_layers, rename_layers};
+        use std::collections::HashMap;
+
+        let mut layer = test_layer(GeomType::Point, vec![vec![(25, 17)]]);
+        layer.keys.push("height".to_string());
+        layer.values.extend(vec![Value::Float(1.5), Value::Double(-2.25), Value::Int(-3), Value::UInt(4), Value::SInt(-5), Value::Bool(true)]);
+        let tile = Tile{ layers: vec![layer] };
+        let bytes = tile.encode();
+        assert_eq!(Tile::decode(&bytes), Ok(tile.clone()));
+
+        // Concatenated tiles are one tile with all the layers
+        let mut two = bytes.clone();
+        two.extend(bytes);
+        assert_eq!(Tile::decode(&two).unwrap().layers.len(), 2);
+
+        assert!(Tile::decode(&[0x1a, 0x05, 0x0a]).is_err());
+
+        let mut tile = Tile{ layers: vec![test_layer(GeomType::Point, vec![vec![(1, 1)]]), test_layer(GeomType::Point, vec![vec![(2, 2)]])] };
+        tile.layers[1].name = "other".to_string();
+        let filtered = filter_layers(&tile.encode(), &["other".to_string(), "missing".to_string()]).unwrap();
+        assert_eq!(Tile::decode(&filtered).unwrap().layers, vec![tile.layers[1].clone()]);
+        assert_eq!(filter_layers(&tile.encode(), &["missing".to_string()]), Ok(Vec::new()));
+
+        let mut renames = HashMap::new();
+        renames.insert("other".to_string(), "land_other".to_string());
+        let renamed = Tile::decode(&rename_layers(&tile.encode(), &renames).unwrap()).unwrap();
+        assert_eq!(renamed.layers[0], tile.layers[0]);
+        assert_eq!(renamed.layers[1].name, "land_other");
+        assert_eq!(renamed.layers[1].features, tile.layers[1].features);
+    }
+
+    #[test]
+    fn test_overzoom() {
+        use super::{Tile, GeomType, overzoom, ring_area};
+
+        let tile = Tile{ layers: vec![
+            test_layer(GeomType::Point, vec![vec![(100, 100), (3000, 3000)]]),
+        ] };
+        // Top left child: only the first point, scaled
+        let child = overzoom(&tile, 1, 0, 0);
+        assert_eq!(child.layers[0].features[0].decode_geometry(), Ok(vec![vec![(200, 200)]]));
+        // Bottom right child: only the second point
+        let child = overzoom(&tile, 1, 1, 1);
+        assert_eq!(child.layers[0].features[0].decode_geometry(), Ok(vec![vec![(1904, 1904)]]));
+        // Nothing in the top right child, so the layer is removed
+        assert_eq!(overzoom(&tile, 1, 1, 0).layers.len(), 0);
+
+        // A line across the tile is clipped (with the 64 pixel buffer)
+        let tile = Tile{ layers: vec![test_layer(GeomType::LineString, vec![vec![(0, 1024), (4096, 1024)]])] };
+        let child = overzoom(&tile, 1, 0, 0);
+        assert_eq!(child.layers[0].features[0].decode_geometry(), Ok(vec![vec![(0, 2048), (4160, 2048)]]));
+        assert_eq!(child.layers[0].features[0].id, Some(1));
+        assert_eq!(child.layers[0].features[0].tags, vec![0, 0]);
+
+        // A square polygon covering the top left of the tile
+        let tile = Tile{ layers: vec![test_layer(GeomType::Polygon, vec![vec![(0, 0), (3072, 0), (3072, 3072), (0, 3072)]])] };
+        let child = overzoom(&tile, 1, 1, 1);
+        assert_eq!(child.layers[0].features[0].decode_geometry(), Ok(vec![vec![(-64, -64), (2048, -64), (2048, 2048), (-64, 2048)]]));
+        let child = overzoom(&tile, 3, 7, 7);
+        assert_eq!(child.layers.len(), 0);
+
+        // A polygon with a hole (clockwise exterior, anticlockwise hole) keeps the clipped hole
+        let square = |min: i64, max: i64| vec![(min, min), (max, min), (max, max), (min, max)];
+        let hole = |min: i64, max: i64| vec![(min, min), (min, max), (max, max), (max, min)];
+        let tile = Tile{ layers: vec![test_layer(GeomType::Polygon, vec![square(0, 4096), hole(1024, 3072)])] };
+        let rings = overzoom(&tile, 1, 0, 0).layers[0].features[0].decode_geometry().unwrap();
+        assert_eq!(rings.len(), 2);
+        assert!(ring_area(&rings[0]) > 0);
+        assert!(ring_area(&rings[1]) < 0);
+        assert!(rings[1].iter().all(|&(x, y)| x >= 2048 && y >= 2048 && x <= 4160 && y <= 4160));
+
+        // When an exterior ring is clipped away, so are its holes, but the next polygon's are kept
+        let tile = Tile{ layers: vec![test_layer(GeomType::Polygon, vec![square(2560, 4000), hole(3000, 3500), square(100, 1500), hole(500, 1000)])] };
+        let rings = overzoom(&tile, 1, 0, 0).layers[0].features[0].decode_geometry().unwrap();
+        assert_eq!(rings.len(), 2);
+        assert!(ring_area(&rings[0]) > 0);
+        assert!(rings[0].contains(&(200, 200)));
+        assert!(ring_area(&rings[1]) < 0);
+        assert!(rings[1].contains(&(1000, 1000)));
+
+        // Overzooming a lot (e.g. z6 to z30) doesn't overflow, or mix up the exterior & hole. This
+        // child is just to the top left of the hole, which is only in its buffer.
+        let tile = Tile{ layers: vec![test_layer(GeomType::Polygon, vec![square(0, 4096), hole(1024, 3072)])] };
+        let child_xy = (1 << 22) - 1;
+        let rings = overzoom(&tile, 24, child_xy, child_xy).layers[0].features[0].decode_geometry().unwrap();
+        assert_eq!(rings.len(), 2);
+        assert!(ring_area(&rings[0]) > 0);
+        assert!(ring_area(&rings[1]) < 0);
+        assert!(rings[1].iter().all(|&(x, y)| x >= 4096 && y >= 4096 && x <= 4160 && y <= 4160));
+        let rings = overzoom(&tile, 24, 0, 0).layers[0].features[0].decode_geometry().unwrap();
+        assert_eq!(rings.len(), 1);
+        assert!(ring_area(&rings[0]) > 0);
+    }
+
+    #[test]
+    fn test_clip_line_splits() {
+        use super::clip_line;
+
+        // Goes out of the box and comes back in
+        let lines = clip_line(&[(0, 0), (0, 200), (50, 200), (50, 0)], -10, 100);
+        assert_eq!(lines, vec![vec![(0, 0), (0, 100)], vec![(50, 100), (50, 0)]]);
+    }
+
+    #[test]
+    fn test_to_geojson() {
+        use super::{Tile, GeomType, to_geojson};
+        use rustc_serialize::json::Json;
+
+        // A point in the middle of tile 1/1/0, and a square with a hole
+        let mut tile = Tile{ layers: vec![
+            test_layer(GeomType::Point, vec![vec![(2048, 2048)]]),
+            test_layer(GeomType::Polygon, vec![vec![(0, 0), (10, 0), (10, 10), (0, 10)], vec![(2, 2), (2, 4), (4, 4), (4, 2)]]),
+        ] };
+        tile.layers[1].name = "squares".to_string();
+        // OpenMapTiles has its own `layer` property
+        tile.layers[1].keys = vec!["layer".to_string()];
+
+        let geojson = to_geojson(&tile, 1, 1, 0);
+        let features = geojson.find("features").unwrap().as_array().unwrap();
+        assert_eq!(features.len(), 2);
+
+        let point = &features[0];
+        assert_eq!(point.find_path(&["geometry", "type"]), Some(&Json::String("Point".to_string())));
+        let coordinates = point.find_path(&["geometry", "coordinates"]).unwrap().as_array().unwrap();
+        assert!((coordinates[0].as_f64().unwrap() - 90.).abs() < 1e-9);
+        assert!((coordinates[1].as_f64().unwrap() - 66.51326).abs() < 1e-5);
+        assert_eq!(point.find_path(&["properties", "name"]), Some(&Json::String("thing".to_string())));
+        assert_eq!(point.find("layer"), Some(&Json::String("test".to_string())));
+        assert_eq!(point.find("id"), Some(&Json::U64(1)));
+
+        let polygon = &features[1];
+        assert_eq!(polygon.find_path(&["geometry", "type"]), Some(&Json::String("Polygon".to_string())));
+        let rings = polygon.find_path(&["geometry", "coordinates"]).unwrap().as_array().unwrap();
+        assert_eq!(rings.len(), 2);
+        // Closed rings
+        assert_eq!(rings[0].as_array().unwrap().len(), 5);
+        assert_eq!(rings[0][0], rings[0][4]);
+        assert_eq!(polygon.find("layer"), Some(&Json::String("squares".to_string())));
+        assert_eq!(polygon.find_path(&["properties", "layer"]), Some(&Json::String("thing".to_string())));
+    }
+}
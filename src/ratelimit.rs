@@ -0,0 +1,164 @@
+extern crate clap;
+
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use clap::ArgMatches;
+
+/// A simple token bucket. Tokens are refilled at `rate` per second, up to `capacity`. Taking
+/// tokens can make the bucket go negative, in which case the caller has to wait until it has been
+/// paid back.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        // Allow a burst of (up to) one second's worth of tokens
+        let capacity = if rate < 1. { 1. } else { rate };
+        TokenBucket{ rate: rate, capacity: capacity, tokens: capacity, last_refill: Instant::now() }
+    }
+
+    /// Take `amount` tokens from the bucket, and return how long the caller should wait before
+    /// continuing.
+    fn take(&mut self, amount: f64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.;
+        self.last_refill = now;
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.tokens -= amount;
+
+        if self.tokens >= 0. {
+            Duration::new(0, 0)
+        } else {
+            secs_to_duration(-self.tokens / self.rate)
+        }
+    }
+}
+
+fn secs_to_duration(secs: f64) -> Duration {
+    Duration::new(secs.trunc() as u64, (secs.fract() * 1_000_000_000.) as u32)
+}
+
+/// Limits how many requests, and how many bytes, are sent to/received from upstreams. One
+/// RateLimiter is shared between all threads, so the limits are global for the whole process.
+#[derive(Debug)]
+pub struct RateLimiter {
+    requests: Option<Mutex<TokenBucket>>,
+    bytes: Option<Mutex<TokenBucket>>,
+}
+
+impl RateLimiter {
+    /// Construct a new RateLimiter. `None` means no limit.
+    pub fn new(max_requests_per_sec: Option<f64>, max_bytes_per_sec: Option<f64>) -> Self {
+        RateLimiter{
+            requests: max_requests_per_sec.map(|r| Mutex::new(TokenBucket::new(r))),
+            bytes: max_bytes_per_sec.map(|r| Mutex::new(TokenBucket::new(r))),
+        }
+    }
+
+    /// A RateLimiter which never waits
+    #[allow(unused)]
+    pub fn unlimited() -> Self {
+        RateLimiter::new(None, None)
+    }
+
+    /// Construct a RateLimiter from the `--max-requests-per-sec` & `--max-bandwidth` command line
+    /// options. Exits if they are invalid.
+    pub fn from_options(options: &ArgMatches) -> Self {
+        let max_requests_per_sec: Option<f64> = options.value_of("max-requests-per-sec").map(|r| {
+            match r.parse::<f64>() {
+                Ok(r) if r > 0. => r,
+                _ => {
+                    println!("Invalid --max-requests-per-sec {:?}", r);
+                    ::std::process::exit(1);
+                }
+            }
+        });
+        let max_bytes_per_sec: Option<f64> = options.value_of("max-bandwidth").map(|b| {
+            match parse_bandwidth(b) {
+                Some(b) => b,
+                None => {
+                    println!("Invalid --max-bandwidth {:?}", b);
+                    ::std::process::exit(1);
+                }
+            }
+        });
+
+        RateLimiter::new(max_requests_per_sec, max_bytes_per_sec)
+    }
+
+    /// Block until we are allowed to send another request.
+    pub fn wait_for_request(&self) {
+        let mut wait = Duration::new(0, 0);
+        if let Some(ref requests) = self.requests {
+            wait = requests.lock().unwrap().take(1.);
+        }
+        if let Some(ref bytes) = self.bytes {
+            // Don't take any bytes, but wait until previous downloads have been "paid back"
+            wait = ::std::cmp::max(wait, bytes.lock().unwrap().take(0.));
+        }
+        sleep(wait);
+    }
+
+    /// Record that `num_bytes` have been downloaded, blocking if that puts us over the bandwidth
+    /// limit.
+    pub fn record_bytes(&self, num_bytes: usize) {
+        if let Some(ref bytes) = self.bytes {
+            let wait = bytes.lock().unwrap().take(num_bytes as f64);
+            sleep(wait);
+        }
+    }
+}
+
+/// Parse a bandwidth (in bytes per sec) like "500000", "500K", "2M" or "1G". Suffixes are powers
+/// of 1024.
+pub fn parse_bandwidth(s: &str) -> Option<f64> {
+    let s = s.trim();
+    let (number, multiplier) = match s.chars().last() {
+        Some('k') | Some('K') => (&s[..s.len()-1], 1024.),
+        Some('m') | Some('M') => (&s[..s.len()-1], 1024. * 1024.),
+        Some('g') | Some('G') => (&s[..s.len()-1], 1024. * 1024. * 1024.),
+        _ => (s, 1.),
+    };
+    match number.parse::<f64>() {
+        Ok(n) if n > 0. => Some(n * multiplier),
+        _ => None,
+    }
+}
+
+mod test {
+    #[test]
+    fn test_parse_bandwidth() {
+        use super::parse_bandwidth;
+
+        assert_eq!(parse_bandwidth("1000"), Some(1000.));
+        assert_eq!(parse_bandwidth("1k"), Some(1024.));
+        assert_eq!(parse_bandwidth("2M"), Some(2. * 1024. * 1024.));
+        assert_eq!(parse_bandwidth("0.5G"), Some(512. * 1024. * 1024.));
+        assert_eq!(parse_bandwidth(""), None);
+        assert_eq!(parse_bandwidth("M"), None);
+        assert_eq!(parse_bandwidth("0"), None);
+        assert_eq!(parse_bandwidth("-10"), None);
+        assert_eq!(parse_bandwidth("lots"), None);
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        use super::TokenBucket;
+        use std::time::Duration;
+
+        let mut bucket = TokenBucket::new(10.);
+        // Full bucket to start
+        assert_eq!(bucket.take(10.), Duration::new(0, 0));
+        // Now we're in debt, so have to wait
+        assert!(bucket.take(5.) > Duration::from_millis(400));
+    }
+}
@@ -6,15 +6,18 @@ extern crate slippy_map_tiles;
 use std::io::Read;
 use std::fs;
 use std::fs::File;
-use std::path::Path;
-use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
 use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
 use hyper::Server;
 use hyper::server::Request;
 use hyper::server::Response;
-use hyper::header::{ContentType, AccessControlAllowOrigin};
-use hyper::mime::{Mime, TopLevel, SubLevel};
+use hyper::header::{ContentType, CacheControl, CacheDirective};
+use hyper::mime::{Mime, TopLevel, SubLevel, Attr, Value};
 
 use rustc_serialize::json;
 
@@ -22,7 +25,21 @@ use clap::ArgMatches;
 
 use slippy_map_tiles::Tile;
 
-use utils::{save_to_file, download_url, URL, parse_url, URLPathPrefix, merge_vector_tiles, DirectoryLayout, IompairTileJsonError};
+use upstream::Upstream;
+use aliases::Aliases;
+use cors::CorsPolicy;
+use utils::{save_to_file, download_url, download_url_with_validators, download_url_and_save_to_file, is_stale, touch, SaveOutcome, HttpOptions, URL, parse_url, URLPathPrefix, merge_vector_tiles, maybe_gunzip, gzip, DirectoryLayout, IompairTileJsonError};
+use mvt;
+use style::rewrite_style;
+
+/// The map viewer page (`--viewer`)
+const VIEWER_HTML: &'static str = include_str!("viewer.html");
+
+/// How long clients can cache TileJSON (& other JSON) responses for
+const JSON_MAX_AGE_SECS: u32 = 300;
+
+/// At most this many merged TileJSONs are cached
+const MAX_CACHED_TILEJSONS: usize = 1000;
 
 pub fn serve(options: &ArgMatches) {
 
@@ -31,20 +48,41 @@ pub fn serve(options: &ArgMatches) {
     let path = options.value_of("tc_path").or(options.value_of("ts_path")).or(options.value_of("zxy_path")).unwrap().to_string();
     let path_format = if options.is_present("tc_path") { DirectoryLayout::TCPath } else if options.is_present("ts_path") { DirectoryLayout::TSPath } else if options.is_present("zxy_path") { DirectoryLayout::ZXYPath } else { unreachable!() };
     let maxzoom: u8 = options.value_of("maxzoom").unwrap().parse().unwrap();
+    // Tiles past maxzoom are made from their maxzoom ancestor, up to this zoom
+    let overzoom_to: u8 = match options.value_of("overzoom_to") {
+        None => maxzoom,
+        Some(z) => match z.parse() {
+            Ok(z) if z >= maxzoom && z <= 30 => z,
+            _ => {
+                println!("Invalid --overzoom-to {:?}, it must be between --max-zoom ({}) and 30", z, maxzoom);
+                ::std::process::exit(1);
+            },
+        },
+    };
     let urlprefix = options.value_of("urlprefix").unwrap_or(&format!("http://localhost:{}/", port)).to_string();
     let verbose = options.is_present("verbose");
     let post_fetch_command: Option<String> = options.value_of("post-fetch-command").map(|s| s.to_string());
+    let tilejson_cache = TileJsonCache::new();
+    let aliases = Aliases::from_options(options);
+    let asset_dirs = AssetDirs::from_options(options, &path);
+    let viewer = options.is_present("viewer");
+    let cors = CorsPolicy::from_options(options);
     
-    let upstreams = parse_out_upstreams(options.values_of("upstream_url"));
+    let upstreams = parse_out_upstreams(options);
+    let http = Arc::new(HttpOptions::from_options(options));
+    let refresher = BackgroundRefresher::new(options.value_of("max_background_refreshes").unwrap().parse().unwrap());
 
-    ensure_tilejson_files_exist_and_upstreams_work(&path, &upstreams);
+    check_upstreams_and_fetch_tilejson(&path, &upstreams, &http);
+    let upstreams = Arc::new(upstreams);
+    let health_check_interval = Duration::new(options.value_of("health_check_interval").unwrap().parse().unwrap(), 0);
+    start_health_checks(path.clone(), upstreams.clone(), http.clone(), health_check_interval);
 
     println!("Serving on port {} with the following upstreams {:?}", port, upstreams);
     let uri = format!("127.0.0.1:{}", port);
     match Server::http(&uri[..]) {
         Err(e) => { println!("Error setting up server: {:?}", e); }
         Ok(server) => {
-            let startup = server.handle(move |req: Request, res: Response| { base_handler(req, res, path_format, &path, maxzoom, &urlprefix, verbose, &upstreams, &post_fetch_command) });
+            let startup = server.handle(move |req: Request, res: Response| { base_handler(req, res, path_format, &path, maxzoom, overzoom_to, &aliases, &asset_dirs, viewer, &cors, &urlprefix, verbose, &upstreams, &post_fetch_command, &http, &refresher, &tilejson_cache) });
             if let Err(e) = startup {
                 println!("Error when starting server: {:?}", e);
             }
@@ -52,164 +90,741 @@ pub fn serve(options: &ArgMatches) {
     }
 }
 
-/// Look at all the upstreams specified, and confirm that those upstreams work, by downloading the
-/// tilejson data. If that local tilejson file doesn't exist, then that will be saved locally for
-/// future use.
-fn ensure_tilejson_files_exist_and_upstreams_work(path: &str, upstreams: &HashMap<String, String>) {
-    for (prefix, upstream_url) in upstreams {
-        let tilejson_url = format!("{}/index.json", upstream_url);
-        let tilejson_path = if Path::new(&format!("{}/{}/metadata.json", path, prefix)).exists() {
-            format!("{}/{}/metadata.json", path, prefix)
-        } else {
-            format!("{}/{}/index.json", path, prefix)
-        };
-        let tilejson_path = Path::new(&tilejson_path);
+/// The directories that styles, sprites & glyphs are served from
+struct AssetDirs {
+    styles: PathBuf,
+    sprites: PathBuf,
+    glyphs: PathBuf,
+}
 
-        match download_url(&tilejson_url, 5) {
-            Err(e) => {
-                println!("Upstream tile source ({}) for prefix \"{}\" isn't working. Error {:?} when trying to download url {}", upstream_url, prefix, e, tilejson_url);
-                ::std::process::exit(2);
-            },
-            Ok(bytes) => {
-                if ! tilejson_path.exists() {
-                    match save_to_file(tilejson_path, &bytes) {
-                        Ok(_) => {
-                            println!("Downloaded tilejson for prefix {}, saved to {:?}", prefix, tilejson_path)
-                        },
-                        Err(e) => {
-                            println!("Error downloading the tilejson for prefix {}, Error was: {:?}.\nExiting", prefix, e);
-                            ::std::process::exit(1);
+impl AssetDirs {
+    /// From `--styles-dir`, `--sprites-dir` & `--glyphs-dir`, defaulting to `styles`, `sprites` &
+    /// `fonts` in the tile cache directory.
+    fn from_options(options: &ArgMatches, path: &str) -> Self {
+        let dir = |option: &str, default: &str| options.value_of(option).map(PathBuf::from).unwrap_or_else(|| Path::new(path).join(default));
+        AssetDirs{ styles: dir("styles_dir", "styles"), sprites: dir("sprites_dir", "sprites"), glyphs: dir("glyphs_dir", "fonts") }
+    }
+}
+
+/// Where the TileJSON for this prefix is stored locally
+fn local_tilejson_path(path: &str, prefix: &str) -> PathBuf {
+    let metadata_path = PathBuf::from(format!("{}/{}/metadata.json", path, prefix));
+    if metadata_path.exists() {
+        metadata_path
+    } else {
+        PathBuf::from(format!("{}/{}/index.json", path, prefix))
+    }
+}
+
+/// Look at all the upstreams (and their mirrors) specified, and check that they work, by
+/// downloading the tilejson data. If that local tilejson file doesn't exist, then that will be
+/// saved locally for future use. The tile URLs, zooms & bounds in the tilejson are used for that
+/// upstream.
+///
+/// Mirrors which don't work won't be used until they do. If no mirror for a prefix works, tiles
+/// are still served from the local cache.
+fn check_upstreams_and_fetch_tilejson(path: &str, upstreams: &HashMap<String, Upstream>, http: &HttpOptions) {
+    for (prefix, upstream) in upstreams.iter() {
+        let tilejson_path = local_tilejson_path(path, prefix);
+        let mut have_tilejson = false;
+
+        for mirror in 0..upstream.num_mirrors() {
+            let tilejson_url = match upstream.mirror_tilejson_url(mirror) {
+                Some(u) => u.to_string(),
+                None => {
+                    println!("No TileJSON URL for prefix \"{}\", so cannot check that the upstream works. Use --upstream-tilejson to set one", prefix);
+                    continue;
+                }
+            };
+
+            match download_url(&tilejson_url, 5, http, upstream.headers_for(&tilejson_url)) {
+                Err(e) => {
+                    println!("Upstream tile source for prefix \"{}\" isn't working, so it won't be used until it does. Error {:?} when trying to download url {}", prefix, e, tilejson_url);
+                    upstream.record_mirror_health(mirror, false);
+                },
+                Ok(bytes) => {
+                    match String::from_utf8(bytes.clone()).ok().and_then(|s| json::Json::from_str(&s).ok()) {
+                        Some(tilejson) => upstream.update_mirror_from_tilejson(mirror, &tilejson),
+                        None => println!("Upstream TileJSON for prefix \"{}\" is not valid JSON, ignoring it", prefix),
+                    }
+
+                    if ! have_tilejson && ! tilejson_path.exists() {
+                        match save_to_file(&tilejson_path, &bytes) {
+                            Ok(_) => {
+                                println!("Downloaded tilejson for prefix {}, saved to {:?}", prefix, tilejson_path)
+                            },
+                            Err(e) => {
+                                println!("Error downloading the tilejson for prefix {}, Error was: {:?}.\nExiting", prefix, e);
+                                ::std::process::exit(1);
+                            }
                         }
                     }
+                    have_tilejson = true;
+                },
+            }
+        }
+
+        if ! have_tilejson && upstream.mirror_tilejson_url(0).is_some() {
+            if tilejson_path.exists() {
+                // Use the zooms & bounds from the last time
+                let local_tilejson = fs::File::open(&tilejson_path).ok().and_then(|mut f| {
+                    let mut s = String::new();
+                    f.read_to_string(&mut s).ok().and_then(|_| json::Json::from_str(&s).ok())
+                });
+                if let Some(local_tilejson) = local_tilejson {
+                    upstream.update_coverage_from_tilejson(&local_tilejson);
+                }
+            }
+            println!("No upstream for prefix \"{}\" is working. Serving from the local cache until one does", prefix);
+        }
+    }
+}
+
+/// Every `interval`, check the mirrors which have failed by downloading their TileJSON, so that
+/// they are used again once they work (with the tile URLs, and if need be the zooms & bounds, from
+/// that TileJSON). If there is no local TileJSON for a prefix yet (because no mirror worked at
+/// startup), it is saved.
+fn start_health_checks(path: String, upstreams: Arc<HashMap<String, Upstream>>, http: Arc<HttpOptions>, interval: Duration) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+            for (prefix, upstream) in upstreams.iter() {
+                let tilejson_path = local_tilejson_path(&path, prefix);
+                for mirror in 0..upstream.num_mirrors() {
+                    let tilejson_url = match upstream.mirror_tilejson_url(mirror) { Some(u) => u, None => { continue; } };
+                    if ! upstream.mirror_has_failed(mirror) && tilejson_path.exists() {
+                        continue;
+                    }
+
+                    match download_url(tilejson_url, 1, &http, upstream.headers_for(tilejson_url)) {
+                        Err(_) => { upstream.record_mirror_health(mirror, false); },
+                        Ok(bytes) => {
+                            if upstream.mirror_has_failed(mirror) {
+                                println!("Upstream {} for prefix \"{}\" is working again", tilejson_url, prefix);
+                            }
+                            match String::from_utf8(bytes.clone()).ok().and_then(|s| json::Json::from_str(&s).ok()) {
+                                Some(tilejson) => upstream.update_mirror_from_tilejson(mirror, &tilejson),
+                                None => println!("Upstream TileJSON {} for prefix \"{}\" is not valid JSON, ignoring it", tilejson_url, prefix),
+                            }
+                            upstream.record_mirror_health(mirror, true);
+                            if ! tilejson_path.exists() {
+                                save_to_file(&tilejson_path, &bytes).unwrap_or_else(|e| {
+                                    println!("Error saving the tilejson for prefix {}: {:?}", prefix, e);
+                                });
+                            }
+                        },
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// The `?layers=` query string for this layer filter, if any
+fn layers_query(layers: &Option<Vec<String>>) -> String {
+    match *layers {
+        None => String::new(),
+        Some(ref layers) => {
+            let mut query = "?layers=".to_string();
+            for byte in layers.join(",").bytes() {
+                match byte {
+                    b'a' ... b'z' | b'A' ... b'Z' | b'0' ... b'9' | b'-' | b'_' | b'.' | b',' => query.push(byte as char),
+                    _ => query.push_str(&format!("%{:02X}", byte)),
+                }
+            }
+            query
+        }
+    }
+}
+
+fn tilejson_contents(path: &str, urlprefix: &str, pathprefix: &URLPathPrefix, maxzoom: u8, overzoom_to: u8, layers: &Option<Vec<String>>, aliases: &Aliases, tilejson_cache: &TileJsonCache) -> Result<String, IompairTileJsonError> {
+    let tilejson = try!(tilejson_object(path, urlprefix, pathprefix, maxzoom, overzoom_to, layers, aliases, tilejson_cache));
+    json::encode(&tilejson).map_err(IompairTileJsonError::JsonEncoderError)
+}
+
+/// Read the TileJSON file (`index.json`, or else `metadata.json`) in this directory
+fn read_tilejson(directory: &str) -> Result<json::Object, IompairTileJsonError> {
+    // TODO do proper std::path stuff here, instead of string concat
+    let tilejson_path = if Path::new(&format!("{}/index.json", directory)).exists() {
+        format!("{}/index.json", directory)
+    } else {
+        format!("{}/metadata.json", directory)
+    };
+
+    let mut f = try!(File::open(tilejson_path).map_err(IompairTileJsonError::OpenFileError));
+    let mut s = String::new();
+    try!(f.read_to_string(&mut s).map_err(IompairTileJsonError::ReadFileError));
+
+    let tilejson = try!(json::Json::from_str(&s).map_err(IompairTileJsonError::InvalidJsonError));
+    tilejson.as_object().cloned().ok_or(IompairTileJsonError::NoJSONObjectError)
+}
+
+/// The TileJSONs of all the parts of this prefix, with the name of each part ("" if there is no
+/// prefix)
+fn read_tilejsons(path: &str, pathprefix: &URLPathPrefix) -> Result<Vec<(String, json::Object)>, IompairTileJsonError> {
+    let names = if pathprefix.len() == 0 { vec![String::new()] } else { pathprefix.parts() };
+    let mut tilejsons = Vec::with_capacity(names.len());
+    for (name, directory) in names.into_iter().zip(pathprefix.paths(path).into_iter()) {
+        tilejsons.push((name, try!(read_tilejson(&directory))));
+    }
+    Ok(tilejsons)
+}
+
+/// The TileJSON files which the TileJSON for this prefix is made from (whether they exist or not)
+fn tilejson_files(path: &str, pathprefix: &URLPathPrefix) -> Vec<PathBuf> {
+    pathprefix.paths(path).iter().flat_map(|directory| vec![Path::new(directory).join("index.json"), Path::new(directory).join("metadata.json")].into_iter()).collect()
+}
+
+/// Caches things made from some files, until any of the files change (or appear or disappear).
+struct FileCache<T> {
+    entries: Mutex<HashMap<String, (Vec<Option<SystemTime>>, T)>>,
+}
+
+impl<T: Clone> FileCache<T> {
+    fn new() -> Self {
+        FileCache{ entries: Mutex::new(HashMap::new()) }
+    }
+
+    /// The cached value for this key, or else the result of `make` (which is cached if it's Ok)
+    fn get<E, F>(&self, key: &str, files: &[PathBuf], make: F) -> Result<T, E> where F: FnOnce() -> Result<T, E> {
+        let mtimes: Vec<Option<SystemTime>> = files.iter().map(|f| f.metadata().and_then(|m| m.modified()).ok()).collect();
+        if let Some(&(ref cached_mtimes, ref value)) = self.entries.lock().unwrap().get(key) {
+            if *cached_mtimes == mtimes {
+                return Ok(value.clone());
+            }
+        }
+
+        let value = try!(make());
+        let mut entries = self.entries.lock().unwrap();
+        // Don't grow forever if lots of different prefixes & layers are asked for
+        if entries.len() >= MAX_CACHED_TILEJSONS {
+            entries.clear();
+        }
+        entries.insert(key.to_string(), (mtimes, value.clone()));
+        Ok(value)
+    }
+}
+
+/// Caches the TileJSON files, and the merged TileJSONs made from them, so they aren't read &
+/// parsed for every request
+struct TileJsonCache {
+    tilejsons: FileCache<Arc<Vec<(String, json::Object)>>>,
+    merged: FileCache<String>,
+}
+
+impl TileJsonCache {
+    fn new() -> Self {
+        TileJsonCache{ tilejsons: FileCache::new(), merged: FileCache::new() }
+    }
+
+    /// Like `read_tilejsons`, but cached
+    fn tilejsons(&self, path: &str, pathprefix: &URLPathPrefix) -> Result<Arc<Vec<(String, json::Object)>>, IompairTileJsonError> {
+        self.tilejsons.get(&pathprefix.parts().join("__"), &tilejson_files(path, pathprefix), || read_tilejsons(path, pathprefix).map(Arc::new))
+    }
+}
+
+/// The ids of the `vector_layers` in this TileJSON
+fn layer_ids(tilejson: &json::Object) -> Vec<String> {
+    tilejson.get("vector_layers").and_then(|v| v.as_array()).map(|v| {
+        v.iter().filter_map(|layer| layer.find("id").and_then(|id| id.as_string()).map(|id| id.to_string())).collect()
+    }).unwrap_or(Vec::new())
+}
+
+/// Layer names must be unique in a tile, so when tilesets are merged, a layer which is already in
+/// an earlier tileset is renamed to `PREFIX_LAYER`. Returns the renames for each tileset.
+fn layer_renames(tilejsons: &[(String, json::Object)]) -> Vec<HashMap<String, String>> {
+    let mut seen = HashSet::new();
+    tilejsons.iter().map(|&(ref prefix, ref tilejson)| {
+        let mut renames = HashMap::new();
+        for id in layer_ids(tilejson) {
+            if seen.contains(&id) {
+                let mut new_id = format!("{}_{}", prefix, id);
+                while seen.contains(&new_id) {
+                    new_id = format!("{}_{}", prefix, new_id);
+                }
+                seen.insert(new_id.clone());
+                renames.insert(id, new_id);
+            } else {
+                seen.insert(id);
+            }
+        }
+        renames
+    }).collect()
+}
+
+/// Add this (string) value to `values`, if it's not empty or already there
+fn add_if_new(values: &mut Vec<String>, value: Option<&json::Json>) {
+    if let Some(value) = value.and_then(|v| v.as_string()) {
+        if ! value.trim().is_empty() && ! values.iter().any(|v| v == value) {
+            values.push(value.to_string());
+        }
+    }
+}
+
+/// Merge several TileJSONs into one: the union of the `bounds`, the lowest `minzoom`, the highest
+/// `maxzoom`, all the (different) `attribution`s & `description`s, and all the `vector_layers`
+/// (renamed with `renames`). Other fields come from the first one.
+fn merge_tilejsons(tilejsons: &[(String, json::Object)], renames: &[HashMap<String, String>]) -> Result<json::Object, IompairTileJsonError> {
+    let mut merged = try!(tilejsons.first().map(|&(_, ref t)| t.clone()).ok_or(IompairTileJsonError::NoTilesetsError));
+    merged.insert("tilejson".to_owned(), json::Json::String("3.0.0".to_owned()));
+
+    let mut bounds: Option<[f64; 4]> = None;
+    let mut minzoom: Option<u64> = None;
+    let mut maxzoom: Option<u64> = None;
+    let mut attributions: Vec<String> = Vec::new();
+    let mut descriptions: Vec<String> = Vec::new();
+    let mut vector_layers = Vec::new();
+
+    for (&(ref prefix, ref tilejson), renames) in tilejsons.iter().zip(renames.iter()) {
+        if let Some(b) = tilejson.get("bounds") {
+            let b: Vec<f64> = try!(b.as_array().and_then(|b| b.iter().map(|x| x.as_f64()).collect::<Option<Vec<f64>>>()).and_then(|b| if b.len() == 4 { Some(b) } else { None })
+                                   .ok_or(IompairTileJsonError::InvalidFieldError(prefix.clone(), "bounds")));
+            bounds = Some(match bounds {
+                None => [b[0], b[1], b[2], b[3]],
+                Some(u) => [u[0].min(b[0]), u[1].min(b[1]), u[2].max(b[2]), u[3].max(b[3])],
+            });
+        }
+        if let Some(z) = tilejson.get("minzoom") {
+            let z = try!(z.as_u64().ok_or(IompairTileJsonError::InvalidFieldError(prefix.clone(), "minzoom")));
+            minzoom = Some(minzoom.map_or(z, |m| ::std::cmp::min(m, z)));
+        }
+        if let Some(z) = tilejson.get("maxzoom") {
+            let z = try!(z.as_u64().ok_or(IompairTileJsonError::InvalidFieldError(prefix.clone(), "maxzoom")));
+            maxzoom = Some(maxzoom.map_or(z, |m| ::std::cmp::max(m, z)));
+        }
+        add_if_new(&mut attributions, tilejson.get("attribution"));
+        add_if_new(&mut descriptions, tilejson.get("description"));
+        match tilejson.get("vector_layers") {
+            None => {},
+            Some(&json::Json::Array(ref layers)) => {
+                for layer in layers {
+                    let mut layer = try!(layer.as_object().cloned().ok_or(IompairTileJsonError::InvalidFieldError(prefix.clone(), "vector_layers")));
+                    let new_id = layer.get("id").and_then(|id| id.as_string()).and_then(|id| renames.get(id)).cloned();
+                    if let Some(new_id) = new_id {
+                        layer.insert("id".to_owned(), json::Json::String(new_id));
+                    }
+                    vector_layers.push(json::Json::Object(layer));
                 }
             },
+            Some(_) => { return Err(IompairTileJsonError::InvalidFieldError(prefix.clone(), "vector_layers")); },
         }
     }
+
+    if let Some(b) = bounds {
+        merged.insert("bounds".to_owned(), json::Json::Array(b.iter().map(|&x| json::Json::F64(x)).collect()));
+    }
+    if let Some(z) = minzoom {
+        merged.insert("minzoom".to_owned(), json::Json::U64(z));
+    }
+    if let Some(z) = maxzoom {
+        merged.insert("maxzoom".to_owned(), json::Json::U64(z));
+    }
+    if ! attributions.is_empty() {
+        merged.insert("attribution".to_owned(), json::Json::String(attributions.join("; ")));
+    }
+    if ! descriptions.is_empty() {
+        merged.insert("description".to_owned(), json::Json::String(descriptions.join("\n")));
+    }
+    merged.insert("vector_layers".to_owned(), json::Json::Array(vector_layers));
+
+    Ok(merged)
 }
 
-fn tilejson_contents(path: &str, urlprefix: &str, pathprefix: &URLPathPrefix, maxzoom: u8) -> Result<String, IompairTileJsonError> {
-    // FIXME Remove the unwraps and replace with proper error handling
-    let new_tiles = json::Json::from_str(&format!("[\"{}{}{{z}}/{{x}}/{{y}}.pbf\"]", urlprefix, pathprefix.path_with_trailing_slash())).unwrap();
-    let zoom_element = json::Json::U64(maxzoom as u64);
+/// The merged TileJSON for this prefix. The `maxzoom` is the highest of the tilesets (or
+/// `maxzoom` if they don't say), but `overzoom_to` when overzooming, since that's how far we serve.
+fn tilejson_object(path: &str, urlprefix: &str, pathprefix: &URLPathPrefix, maxzoom: u8, overzoom_to: u8, layers: &Option<Vec<String>>, aliases: &Aliases, tilejson_cache: &TileJsonCache) -> Result<json::Object, IompairTileJsonError> {
+    let tilejsons = try!(tilejson_cache.tilejsons(path, pathprefix));
+    let mut tilejson = try!(merge_tilejsons(&tilejsons, &layer_renames(&tilejsons)));
 
-    let sub_paths = pathprefix.paths(path);
+    let tiles = json::Json::Array(vec![json::Json::String(format!("{}{}{{z}}/{{x}}/{{y}}.pbf{}", urlprefix, pathprefix.path_with_trailing_slash(), layers_query(layers)))]);
+    tilejson.insert("tiles".to_owned(), tiles);
 
-    let mut tilejson_contents = Vec::with_capacity(sub_paths.len());
+    let tilesets_maxzoom = tilejson.get("maxzoom").and_then(|z| z.as_u64()).unwrap_or(maxzoom as u64);
+    let served_maxzoom = if overzoom_to > maxzoom { overzoom_to as u64 } else { ::std::cmp::min(tilesets_maxzoom, overzoom_to as u64) };
+    tilejson.insert("maxzoom".to_owned(), json::Json::U64(served_maxzoom));
 
-    // Collect all the existing tilejsons
-    for directory in sub_paths {
+    // An alias can have its own name & attribution
+    if let Some(alias) = pathprefix.alias().and_then(|a| aliases.get(a)) {
+        if let Some(ref name) = alias.name {
+            tilejson.insert("name".to_owned(), json::Json::String(name.clone()));
+        }
+        if let Some(ref attribution) = alias.attribution {
+            tilejson.insert("attribution".to_owned(), json::Json::String(attribution.clone()));
+        }
+    }
 
-        // FIXME don't fall over if there is no file
-        // TODO do proper std::path stuff here, instead of string concat
-        let tilejson_path = if Path::new(&format!("{}/index.json", directory)).exists() {
-            format!("{}/index.json", directory)
-        } else {
-            format!("{}/metadata.json", directory)
-        };
+    // Only describe the layers which will be in the tiles
+    if let Some(ref layers) = *layers {
+        if let Some(vector_layers) = tilejson.get_mut("vector_layers").and_then(|v| v.as_array_mut()) {
+            vector_layers.retain(|layer| layer.find("id").and_then(|id| id.as_string()).map_or(false, |id| layers.iter().any(|l| l == id)));
+        }
+    }
 
-        let mut f = try!(File::open(tilejson_path).map_err(IompairTileJsonError::OpenFileError));
-        let mut s = String::new();
-        try!(f.read_to_string(&mut s).map_err(IompairTileJsonError::ReadFileError));
+    Ok(tilejson)
+}
+
+/// Does this directory have a TileJSON file?
+fn has_tilejson(directory: &Path) -> bool {
+    directory.join("index.json").exists() || directory.join("metadata.json").exists()
+}
 
-        // Some back and forth to decode, replace and encode to get the new tilejson string
-        let tilejson_0 = try!(json::Json::from_str(&s).map_err(IompairTileJsonError::InvalidJsonError));
-        let mut tilejson = try!(tilejson_0.as_object().ok_or(IompairTileJsonError::NoJSONObjectError)).to_owned();
-        tilejson.insert("tiles".to_owned(), new_tiles.clone());
-        tilejson.insert("maxzoom".to_owned(), zoom_element.clone());
-        tilejson_contents.push(tilejson);
+/// A list of all the tilesets, i.e. the prefixes (subdirectories with a TileJSON file) and the
+/// aliases, with their TileJSON URL, zooms, bounds & layers.
+fn catalog_contents(path: &str, urlprefix: &str, maxzoom: u8, overzoom_to: u8, aliases: &Aliases, tilejson_cache: &TileJsonCache) -> Result<String, IompairTileJsonError> {
+    let mut prefixes = Vec::new();
+    for entry in try!(fs::read_dir(path).map_err(IompairTileJsonError::OpenFileError)) {
+        let entry = try!(entry.map_err(IompairTileJsonError::ReadFileError));
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_valid_prefix = ! name.is_empty() && ! name.contains("__") && name.chars().all(|c| match c { 'a' ... 'z' | 'A' ... 'Z' | '0' ... '9' | '_' | '-' => true, _ => false });
+        if is_valid_prefix && aliases.get(&name).is_none() && has_tilejson(&entry.path()) {
+            prefixes.push(name);
+        }
     }
+    prefixes.sort();
+    prefixes.extend(aliases.names().into_iter().map(|a| a.to_string()));
 
-    // now create a new one with the merged vector_layers attribute
-    // Copy the first one as base.
-    let mut tilejson_base = tilejson_contents.remove(0);
-    for mut tilejson in tilejson_contents.into_iter() {
-        let mut vector_layers = tilejson.remove("vector_layers").unwrap();
-        let mut vector_layers = vector_layers.as_array_mut().unwrap();
-        tilejson_base.get_mut("vector_layers").unwrap().as_array_mut().unwrap().append(vector_layers);
+    let mut tilesets = Vec::with_capacity(prefixes.len());
+    for prefix in prefixes {
+        let pathprefix = match URLPathPrefix::parse(Some(prefix.clone()), aliases) {
+            Some(p) => p,
+            None => { continue; },
+        };
+        let tilejson = match tilejson_object(path, urlprefix, &pathprefix, maxzoom, overzoom_to, &None, aliases, tilejson_cache) {
+            Ok(t) => t,
+            Err(e) => {
+                println!("Leaving {} out of the catalogue, error reading its TileJSON: {:?}", prefix, e);
+                continue;
+            },
+        };
+
+        let mut tileset = json::Object::new();
+        tileset.insert("id".to_owned(), json::Json::String(prefix.clone()));
+        tileset.insert("name".to_owned(), tilejson.get("name").cloned().unwrap_or(json::Json::String(prefix.clone())));
+        tileset.insert("tilejson".to_owned(), json::Json::String(format!("{}{}index.json", urlprefix, pathprefix.path_with_trailing_slash())));
+        tileset.insert("minzoom".to_owned(), tilejson.get("minzoom").cloned().unwrap_or(json::Json::U64(0)));
+        tileset.insert("maxzoom".to_owned(), tilejson.get("maxzoom").cloned().unwrap_or(json::Json::U64(maxzoom as u64)));
+        if let Some(bounds) = tilejson.get("bounds") {
+            tileset.insert("bounds".to_owned(), bounds.clone());
+        }
+        tileset.insert("layers".to_owned(), json::Json::Array(layer_ids(&tilejson).into_iter().map(json::Json::String).collect()));
+        tilesets.push(json::Json::Object(tileset));
     }
 
-    let new_tilejson_contents: String = try!(json::encode(&tilejson_base).map_err(IompairTileJsonError::JsonEncoderError));
-    Ok(new_tilejson_contents)
+    let mut catalog = json::Object::new();
+    catalog.insert("tilesets".to_owned(), json::Json::Array(tilesets));
+    json::encode(&catalog).map_err(IompairTileJsonError::JsonEncoderError)
 }
 
-fn base_handler(req: Request, mut res: Response, path_format: DirectoryLayout, path: &str, maxzoom: u8, urlprefix: &str, verbose: bool, upstreams: &HashMap<String, String>, post_fetch_command: &Option<String>) {
+fn base_handler(req: Request, mut res: Response, path_format: DirectoryLayout, path: &str, maxzoom: u8, overzoom_to: u8, aliases: &Aliases, asset_dirs: &AssetDirs, viewer: bool, cors: &CorsPolicy, urlprefix: &str, verbose: bool, upstreams: &Arc<HashMap<String, Upstream>>, post_fetch_command: &Option<String>, http: &Arc<HttpOptions>, refresher: &BackgroundRefresher, tilejson_cache: &TileJsonCache) {
     let mut url: String = String::new();
     if let hyper::uri::RequestUri::AbsolutePath(ref u) = req.uri {
         url = u.clone();
     }
+
+    if req.method == hyper::method::Method::Options {
+        cors.apply_preflight(&req.headers, res.headers_mut());
+        *res.status_mut() = hyper::status::StatusCode::NoContent;
+        return;
+    }
+    cors.apply(&req.headers, res.headers_mut());
         
-    match parse_url(&url, maxzoom) {
-        URL::Tilejson(pathprefix) => {
-            tilejson_handler(res, path, urlprefix, &pathprefix, maxzoom);
+    match parse_url(&url, overzoom_to, aliases) {
+        URL::Catalog(callback) => {
+            catalog_handler(res, path, urlprefix, maxzoom, overzoom_to, aliases, &cors.jsonp_callback(&callback), tilejson_cache);
+            if verbose {
+                println!("/catalog.json");
+            }
+        },
+        URL::Tilejson(ref pathprefix, _, ref callback) if pathprefix.len() == 0 && ! has_tilejson(Path::new(path)) => {
+            // Using prefixes, so there's no TileJSON for the root
+            catalog_handler(res, path, urlprefix, maxzoom, overzoom_to, aliases, &cors.jsonp_callback(callback), tilejson_cache);
+            if verbose {
+                println!("/index.json (catalogue)");
+            }
+        },
+        URL::Tilejson(pathprefix, layers, callback) => {
+            tilejson_handler(res, path, urlprefix, &pathprefix, maxzoom, overzoom_to, &layers, aliases, &cors.jsonp_callback(&callback), tilejson_cache);
             if verbose {
                 println!("{}/index.json", pathprefix);
             }
         },
-        URL::Invalid => {
+        URL::Viewer if viewer => {
+            res.headers_mut().set(ContentType(Mime(TopLevel::Text, SubLevel::Html, vec![(Attr::Charset, Value::Utf8)])));
+            res.send(VIEWER_HTML.as_bytes()).unwrap_or_else(|e| {
+                println!("Error when trying to send viewer to client: {:?}", e);
+            });
+        },
+        URL::Viewer | URL::Invalid => {
             *res.status_mut() = hyper::status::StatusCode::NotFound;
         },
-        URL::Tile(pathprefix, z, x, y, ext) => {
-            tile_handler(res, path_format, path, &pathprefix, z, x, y, ext, &upstreams, post_fetch_command, verbose);
+        URL::Tile(pathprefix, z, x, y, ext, layers) => {
+            tile_handler(res, path_format, path, &pathprefix, z, x, y, ext, &layers, maxzoom, upstreams, post_fetch_command, verbose, http, refresher, tilejson_cache);
+        },
+        URL::GeoJSON(pathprefix, z, x, y, ext, layers) => {
+            geojson_handler(res, path_format, path, &pathprefix, z, x, y, ext, &layers, maxzoom, upstreams, post_fetch_command, verbose, http, refresher, tilejson_cache);
+        },
+        URL::Style(name, callback) => {
+            style_handler(res, &asset_dirs.styles, &name, urlprefix, &cors.jsonp_callback(&callback));
+            if verbose {
+                println!("/styles/{}.json", name);
+            }
+        },
+        URL::Sprite(filename) => {
+            sprite_handler(res, &asset_dirs.sprites, &filename);
+            if verbose {
+                println!("/sprites/{}", filename);
+            }
+        },
+        URL::Glyphs(fontstack, range) => {
+            glyphs_handler(res, &asset_dirs.glyphs, &fontstack, &range);
+            if verbose {
+                println!("/fonts/{}/{}.pbf", fontstack.join(","), range);
+            }
+        },
+    }
+}
+
+/// Run the `--post-fetch-command` (if any) now that we have downloaded this file
+fn run_post_fetch_command(post_fetch_command: &Option<String>, path: &Path, verbose: bool) {
+    if let &Some(ref cmd) = post_fetch_command {
+        Command::new(cmd).arg(path).status().ok();
+        // we don't care about the output status
+        if verbose {
+            println!("Ran the command {} for the file {:?}", cmd, path);
         }
     }
 }
 
-fn tile_handler(mut res: Response, path_format: DirectoryLayout, path: &str, pathprefix: &URLPathPrefix, z: u8, x: u32, y: u32, ext: String, upstreams: &HashMap<String, String>, post_fetch_command: &Option<String>, verbose: bool) {
-    let tile = Tile::new(z, x, y);
-    let tile = try_or_err!(tile.ok_or("ERR"), res, format!("Error when turning z {} x {} y {} into tileobject", z, x, y));
+/// Refreshes cached tiles from the upstream in the background (i.e. stale-while-revalidate). A
+/// tile is only refreshed by one thread at a time, and there is a limit to how many tiles can be
+/// refreshed at once. Requests to the upstream are rate limited like all other requests.
+struct BackgroundRefresher {
+    in_flight: Arc<Mutex<HashSet<PathBuf>>>,
+    max_in_flight: usize,
+}
+
+impl BackgroundRefresher {
+    fn new(max_in_flight: usize) -> Self {
+        BackgroundRefresher{ in_flight: Arc::new(Mutex::new(HashSet::new())), max_in_flight: max_in_flight }
+    }
+
+    /// Mark the tile at `path` as being refreshed, until the returned guard is dropped. `None` if
+    /// it's already being refreshed, or too many tiles are being refreshed.
+    fn start(&self, path: &Path) -> Option<InFlight> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.len() >= self.max_in_flight || in_flight.contains(path) {
+            return None;
+        }
+        in_flight.insert(path.to_path_buf());
+        Some(InFlight{ in_flight: self.in_flight.clone(), path: path.to_path_buf() })
+    }
+
+    /// Start refreshing the tile at `path` from the upstream for `prefix` in a new thread.
+    /// Returns false if it wasn't, because it's already being refreshed, or too many tiles are
+    /// being refreshed.
+    fn refresh(&self, path: &Path, prefix: &str, z: u8, x: u32, y: u32, upstreams: &Arc<HashMap<String, Upstream>>, http: &Arc<HttpOptions>, post_fetch_command: &Option<String>, verbose: bool) -> bool {
+        let in_flight = match self.start(path) {
+            None => { return false; },
+            Some(i) => i,
+        };
+
+        let path = path.to_path_buf();
+        let prefix = prefix.to_string();
+        let upstreams = upstreams.clone();
+        let http = http.clone();
+        let post_fetch_command = post_fetch_command.clone();
+        thread::spawn(move || {
+            // No longer in flight when this thread ends, even if it panics
+            let _in_flight = in_flight;
+            let upstream = &upstreams[&prefix];
+            match upstream.try_mirrors(z, x, y, |url| download_url_and_save_to_file(url, &path, upstream.tries_per_mirror(), &http, upstream.headers_for(url))) {
+                Ok(outcome) => {
+                    if verbose { println!("Refreshed {:?} in the background: {:?}", path, outcome); }
+                    if outcome.is_change() {
+                        run_post_fetch_command(&post_fetch_command, &path, verbose);
+                    } else if outcome == SaveOutcome::Unchanged {
+                        // The file wasn't written to, but we now know it's fresh
+                        touch(&path).unwrap_or_else(|e| println!("Error updating mtime of {:?}: {:?}", path, e));
+                    }
+                },
+                Err(e) => {
+                    if verbose { println!("Error refreshing {:?} in the background: {:?}", path, e); }
+                },
+            }
+        });
+
+        true
+    }
+}
+
+/// A tile which is being refreshed. It's removed from the `BackgroundRefresher`'s set when this
+/// is dropped.
+struct InFlight {
+    in_flight: Arc<Mutex<HashSet<PathBuf>>>,
+    path: PathBuf,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        // Still remove it if another thread panicked while holding the lock
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        in_flight.remove(&self.path);
+    }
+}
+
+/// Was the file at this path last modified more than `max_age` ago?
+fn is_older_than(path: &Path, max_age: Duration) -> bool {
+    match path.metadata().and_then(|m| m.modified()).map(|mtime| SystemTime::now().duration_since(mtime)) {
+        Ok(Ok(age)) => age > max_age,
+        _ => false,
+    }
+}
+
+fn tile_handler(mut res: Response, path_format: DirectoryLayout, path: &str, pathprefix: &URLPathPrefix, z: u8, x: u32, y: u32, ext: String, layers: &Option<Vec<String>>, maxzoom: u8, upstreams: &Arc<HashMap<String, Upstream>>, post_fetch_command: &Option<String>, verbose: bool, http: &Arc<HttpOptions>, refresher: &BackgroundRefresher, tilejson_cache: &TileJsonCache) {
+    let vector_tile = try_or_err!(get_or_overzoom_tile(path_format, path, pathprefix, z, x, y, &ext, layers, maxzoom, upstreams, post_fetch_command, verbose, http, refresher, tilejson_cache), res);
+    let vector_tile = match vector_tile {
+        Some(t) => t,
+        None => {
+            *res.status_mut() = hyper::status::StatusCode::NotFound;
+            return;
+        },
+    };
+
+    *res.status_mut() = hyper::status::StatusCode::Ok;
+    res.headers_mut().set(ContentType(Mime(TopLevel::Application, SubLevel::Ext("x-protobuf".to_owned()), vec![])));
+
+    // FIXME Cache headers? This says "no caching", which is probably not what's wanted
+    //res.headers_mut().set(CacheControl(vec![CacheDirective::Private, CacheDirective::NoCache, CacheDirective::MaxAge(0)]));
+
+    res.send(&vector_tile).unwrap_or_else(|e| {
+        println!("Error when trying to send tilejson to client: {:?}", e);
+    });
+
+    if verbose { println!("{}/{}/{}/{}.pbf", pathprefix, z, x, y); }
+
+}
+
+/// Serve this tile converted to GeoJSON
+fn geojson_handler(mut res: Response, path_format: DirectoryLayout, path: &str, pathprefix: &URLPathPrefix, z: u8, x: u32, y: u32, ext: String, layers: &Option<Vec<String>>, maxzoom: u8, upstreams: &Arc<HashMap<String, Upstream>>, post_fetch_command: &Option<String>, verbose: bool, http: &Arc<HttpOptions>, refresher: &BackgroundRefresher, tilejson_cache: &TileJsonCache) {
+    // The tiles are stored as .pbf files, like the TileJSON says
+    let vector_tile = try_or_err!(get_or_overzoom_tile(path_format, path, pathprefix, z, x, y, "pbf", layers, maxzoom, upstreams, post_fetch_command, verbose, http, refresher, tilejson_cache), res);
+    let vector_tile = match vector_tile {
+        Some(t) => t,
+        None => {
+            *res.status_mut() = hyper::status::StatusCode::NotFound;
+            return;
+        },
+    };
+    let (vector_tile, _) = try_or_err!(maybe_gunzip(&vector_tile), res, format!("Error when uncompressing {}/{}/{}/{}", pathprefix, z, x, y));
+    let vector_tile = try_or_err!(mvt::Tile::decode(&vector_tile), res, format!("Error when decoding {}/{}/{}/{}", pathprefix, z, x, y));
+    let geojson = mvt::to_geojson(&vector_tile, z, x, y).to_string();
+
+    *res.status_mut() = hyper::status::StatusCode::Ok;
+    let subtype = if ext == "geojson" { SubLevel::Ext("geo+json".to_owned()) } else { SubLevel::Json };
+    res.headers_mut().set(ContentType(Mime(TopLevel::Application, subtype, vec![])));
+
+    res.send(geojson.as_bytes()).unwrap_or_else(|e| {
+        println!("Error when trying to send geojson to client: {:?}", e);
+    });
+
+    if verbose { println!("{}/{}/{}/{}.{}", pathprefix, z, x, y, ext); }
+}
+
+/// The (merged) contents of this tile, with only these `layers` (if given). Tiles past `maxzoom`
+/// are made from their ancestor at `maxzoom`, if they are vector tiles (`pbf`), otherwise they
+/// don't exist (`None`).
+fn get_or_overzoom_tile(path_format: DirectoryLayout, path: &str, pathprefix: &URLPathPrefix, z: u8, x: u32, y: u32, ext: &str, layers: &Option<Vec<String>>, maxzoom: u8, upstreams: &Arc<HashMap<String, Upstream>>, post_fetch_command: &Option<String>, verbose: bool, http: &Arc<HttpOptions>, refresher: &BackgroundRefresher, tilejson_cache: &TileJsonCache) -> Result<Option<Vec<u8>>, String> {
+    if z <= maxzoom {
+        let tile = try!(get_tile(path_format, path, pathprefix, z, x, y, ext, upstreams, post_fetch_command, verbose, http, refresher, tilejson_cache));
+        return filter_vector_tile(tile, layers).map(Some).map_err(|e| format!("Error when filtering the layers of {}/{}/{}/{}: {}", pathprefix, z, x, y, e));
+    }
+    if ext != "pbf" {
+        // Only vector tiles can be overzoomed
+        return Ok(None);
+    }
+
+    let dz = z - maxzoom;
+    let (parent_x, parent_y) = (x >> dz, y >> dz);
+    let parent = try!(get_tile(path_format, path, pathprefix, maxzoom, parent_x, parent_y, ext, upstreams, post_fetch_command, verbose, http, refresher, tilejson_cache));
+    let parent = try!(filter_vector_tile(parent, layers).map_err(|e| format!("Error when filtering the layers of {}/{}/{}/{}: {}", pathprefix, maxzoom, parent_x, parent_y, e)));
+    if verbose { println!("Overzooming {}/{}/{}/{} from {}/{}/{}/{}", pathprefix, z, x, y, pathprefix, maxzoom, parent_x, parent_y); }
+    overzoom_vector_tile(&parent, dz, x - (parent_x << dz), y - (parent_y << dz)).map(Some)
+        .map_err(|e| format!("Error when overzooming {}/{}/{}/{}: {}", pathprefix, maxzoom, parent_x, parent_y, e))
+}
+
+/// Only keep these `layers` (if given) of this tile. The result is gzipped if the tile was.
+fn filter_vector_tile(vector_tile: Vec<u8>, layers: &Option<Vec<String>>) -> Result<Vec<u8>, String> {
+    let layers = match *layers {
+        None => { return Ok(vector_tile); },
+        Some(ref layers) => layers,
+    };
+    let (bytes, was_gzipped) = try!(maybe_gunzip(&vector_tile));
+    let filtered = try!(mvt::filter_layers(&bytes, layers));
+    Ok(if was_gzipped && ! filtered.is_empty() { gzip(&filtered) } else { filtered })
+}
+
+/// Make a tile for a zoom `dz` levels deeper than `parent`, for the child (`x`, `y`) (relative to
+/// the parent). The result is gzipped if the parent was.
+fn overzoom_vector_tile(parent: &[u8], dz: u8, x: u32, y: u32) -> Result<Vec<u8>, String> {
+    let (bytes, was_gzipped) = try!(maybe_gunzip(parent));
+    let tile = try!(mvt::Tile::decode(&bytes));
+    let child = mvt::overzoom(&tile, dz, x, y).encode();
+    Ok(if was_gzipped { gzip(&child) } else { child })
+}
+
+/// The (merged) contents of this tile, from the cache directory or else the upstreams. If no
+/// prefix has the tile, it is empty.
+fn get_tile(path_format: DirectoryLayout, path: &str, pathprefix: &URLPathPrefix, z: u8, x: u32, y: u32, ext: &str, upstreams: &Arc<HashMap<String, Upstream>>, post_fetch_command: &Option<String>, verbose: bool, http: &Arc<HttpOptions>, refresher: &BackgroundRefresher, tilejson_cache: &TileJsonCache) -> Result<Vec<u8>, String> {
+    let tile = try!(Tile::new(z, x, y).ok_or(format!("Error when turning z {} x {} y {} into tileobject", z, x, y)));
 
     let mut vector_tiles: Vec<Vec<u8>> = Vec::with_capacity(pathprefix.len());
 
     for prefix in pathprefix.parts() {
 
         let sub_path = format!("{}/{}", path, prefix);
-        let path = format!("{}/{}", sub_path, match path_format {
-            DirectoryLayout::TCPath => tile.tc_path(&ext),
-            DirectoryLayout::TSPath => tile.ts_path(&ext),
-            DirectoryLayout::ZXYPath => tile.zxy_path(&ext),
-        });
+        let path = format!("{}/{}", sub_path, path_format.tile_path(&tile, ext));
         let this_tile_path = Path::new(&path);
 
         // This is a stupid bit of hackery to ensure that s is initialised to /something/
         let mut this_vector_tile_contents: Vec<u8> = Vec::new();
-    
+
+        let upstream = upstreams.get(&prefix).and_then(|u| if u.covers(z, x, y) { Some(u) } else { None });
+
+        if let Some(upstream) = upstream {
+            // Serve old tiles (and ones `expire` has marked as stale) now, and get a new one for
+            // next time. If that fails, a stale tile stays stale, and is tried again next time.
+            let stale = this_tile_path.exists() && is_stale(this_tile_path);
+            if stale || upstream.max_age().map_or(false, |max_age| is_older_than(this_tile_path, max_age)) {
+                let started = refresher.refresh(this_tile_path, &prefix, z, x, y, upstreams, http, post_fetch_command, verbose);
+                if started && verbose { println!("{} tile {}/{}/{}/{}, refreshing in the background", if stale { "Stale" } else { "Old" }, prefix, z, x, y); }
+            }
+        }
+
         if this_tile_path.exists() {
-            let mut file = try_or_err!(fs::File::open(this_tile_path), res, format!("Error when opening file {:?}", this_tile_path));
-            try_or_err!(file.read_to_end(&mut this_vector_tile_contents), res, format!("Error when trying to send vectortile contents to client"));
+            let mut file = try!(fs::File::open(this_tile_path).map_err(|e| format!("Error when opening file {:?}: {:?}", this_tile_path, e)));
+            try!(file.read_to_end(&mut this_vector_tile_contents).map_err(|e| format!("Error when reading file {:?}: {:?}", this_tile_path, e)));
         } else {
             // File not found, look at our upstream sources if this prefix exists (which also
             // handles cases where /no/ upstreams have been specified)
-            // If we don't have any upstream sources for this prefix, then we return (and save)
-            // nothing.
+            // If we don't have any upstream sources for this prefix, or the tile is outside the
+            // zooms/bounds of the upstream, then we return (and save) nothing.
             // TODO are there too many print statements here?
-            if let Some(upstream_prefix) = upstreams.get(&prefix) {
-                let upstream_url = format!("{}/{}/{}/{}.pbf", upstream_prefix, z, x, y);
+            if let Some(upstream) = upstream {
                 if verbose { println!("Cache miss {}/{}/{}/{}, downloading... ", prefix, z, x, y); }
 
-                match download_url(&upstream_url, 10) {
+                match upstream.try_mirrors(z, x, y, |url| download_url_with_validators(url, upstream.tries_per_mirror(), http, upstream.headers_for(url))) {
                     Err(e) => {
-                        if verbose { println!("Cache miss {}/{}/{}/{} and error downloading file: {:?}", prefix, z, x, y, e); }
-                        *res.status_mut() = hyper::status::StatusCode::InternalServerError;
-                        return;
+                        return Err(format!("Cache miss {}/{}/{}/{} and error downloading file: {:?}", prefix, z, x, y, e));
                     }
-                    Ok(mut new_bytes) => {
+                    Ok((mut new_bytes, validators)) => {
                         this_vector_tile_contents.append(&mut new_bytes);
-                        match save_to_file(this_tile_path, &this_vector_tile_contents) {
+                        match save_to_file(this_tile_path, &this_vector_tile_contents).and_then(|_| validators.save(this_tile_path)) {
                             Ok(_) => {
                                 if verbose { println!("Cache miss {}/{}/{}/{} downloaded and saved in {:?}", prefix, z, x, y, this_tile_path); }
-                                if let &Some(ref cmd) = post_fetch_command {
-                                    // Run the command now that we have downloaded the file
-                                    Command::new(cmd).arg(this_tile_path).status().ok();
-                                    // we don't care about the output status
-                                    if verbose {
-                                        println!("Ran the command {} for the file {:?}", cmd, this_tile_path);
-                                    }
-                                }
+                                run_post_fetch_command(post_fetch_command, this_tile_path, verbose);
                             },
                             Err(e) => {
-                                if verbose { println!("Cache miss {}/{}/{}/{} and error downloading file: {:?}", prefix, z, x, y, e); }
-                                *res.status_mut() = hyper::status::StatusCode::InternalServerError;
-                                return;
+                                return Err(format!("Cache miss {}/{}/{}/{} and error saving file: {:?}", prefix, z, x, y, e));
                             },
                         }
                     }
@@ -220,39 +835,138 @@ fn tile_handler(mut res: Response, path_format: DirectoryLayout, path: &str, pat
         vector_tiles.push(this_vector_tile_contents);
     }
 
-    let vector_tile = merge_vector_tiles(vector_tiles);
+    // Rename layers which are in more than one tileset, like the TileJSON does
+    if vector_tiles.len() > 1 {
+        if let Ok(tilejsons) = tilejson_cache.tilejsons(path, pathprefix) {
+            for (vector_tile, renames) in vector_tiles.iter_mut().zip(layer_renames(&tilejsons).into_iter()) {
+                if renames.is_empty() || vector_tile.is_empty() {
+                    continue;
+                }
+                let (bytes, was_gzipped) = try!(maybe_gunzip(vector_tile));
+                let renamed = try!(mvt::rename_layers(&bytes, &renames));
+                *vector_tile = if was_gzipped { gzip(&renamed) } else { renamed };
+            }
+        }
+    }
 
-    *res.status_mut() = hyper::status::StatusCode::Ok;
-    res.headers_mut().set(ContentType(Mime(TopLevel::Application, SubLevel::Ext("x-protobuf".to_owned()), vec![])));
-    res.headers_mut().set(AccessControlAllowOrigin::Any);
+    Ok(merge_vector_tiles(vector_tiles))
+}
 
-    // FIXME Cache headers? This says "no caching", which is probably not what's wanted
-    //res.headers_mut().set(CacheControl(vec![CacheDirective::Private, CacheDirective::NoCache, CacheDirective::MaxAge(0)]));
+fn catalog_handler(mut res: Response, path: &str, urlprefix: &str, maxzoom: u8, overzoom_to: u8, aliases: &Aliases, callback: &Option<String>, tilejson_cache: &TileJsonCache) {
+    match catalog_contents(path, urlprefix, maxzoom, overzoom_to, aliases, tilejson_cache) {
+        Err(e) => {
+            println!("Error when making the catalogue: {:?}", e);
+            *res.status_mut() = hyper::status::StatusCode::InternalServerError;
+        },
+        Ok(json) => {
+            send_json(res, &json, callback);
+        }
+    };
+}
 
-    res.send(&vector_tile).unwrap_or_else(|e| {
-        println!("Error when trying to send tilejson to client: {:?}", e);
+fn tilejson_handler(mut res: Response, path: &str, urlprefix: &str, pathprefix: &URLPathPrefix, maxzoom: u8, overzoom_to: u8, layers: &Option<Vec<String>>, aliases: &Aliases, callback: &Option<String>, tilejson_cache: &TileJsonCache) {
+    let key = format!("{}{}", pathprefix.path_with_trailing_slash(), layers_query(layers));
+    let contents = tilejson_cache.merged.get(&key, &tilejson_files(path, pathprefix), || {
+        tilejson_contents(path, &urlprefix, pathprefix, maxzoom, overzoom_to, layers, aliases, tilejson_cache)
     });
+    match contents {
+        Err(e) => {
+            println!("Error when reading tilejson file to serve up: {:?}", e);
+            *res.status_mut() = hyper::status::StatusCode::InternalServerError;
+        },
+        Ok(json) => {
+            send_json(res, &json, callback);
+        }
+    };
+}
 
-    if verbose { println!("{}/{}/{}/{}.pbf", pathprefix, z, x, y); }
+/// Read a whole file, `None` if it doesn't exist (or can't be read)
+fn read_file(path: &Path) -> Option<Vec<u8>> {
+    let mut file = match File::open(path) { Ok(f) => f, Err(_) => return None };
+    let mut contents = Vec::new();
+    match file.read_to_end(&mut contents) {
+        Ok(_) => Some(contents),
+        Err(e) => {
+            println!("Error when reading {:?}: {:?}", path, e);
+            None
+        },
+    }
+}
 
+/// Send a static file (sprite, glyphs), or a 404 if it doesn't exist
+fn send_file(mut res: Response, contents: Option<Vec<u8>>, mime: Mime) {
+    match contents {
+        None => {
+            *res.status_mut() = hyper::status::StatusCode::NotFound;
+        },
+        Some(contents) => {
+            res.headers_mut().set(CacheControl(vec![CacheDirective::Public, CacheDirective::MaxAge(JSON_MAX_AGE_SECS)]));
+            res.headers_mut().set(ContentType(mime));
+            res.send(&contents).unwrap_or_else(|e| {
+                println!("Error when trying to send file to client: {:?}", e);
+            });
+        },
+    }
 }
 
-fn tilejson_handler(mut res: Response, path: &str, urlprefix: &str, pathprefix: &URLPathPrefix, maxzoom: u8) {
-    match tilejson_contents(path, &urlprefix, pathprefix, maxzoom) {
+/// Serve the style `name`, with its local URLs pointing at this server
+fn style_handler(mut res: Response, styles_dir: &Path, name: &str, urlprefix: &str, callback: &Option<String>) {
+    let contents = match read_file(&styles_dir.join(format!("{}.json", name))) {
+        None => {
+            *res.status_mut() = hyper::status::StatusCode::NotFound;
+            return;
+        },
+        Some(c) => c,
+    };
+    match String::from_utf8(contents).map_err(|e| e.to_string()).and_then(|c| rewrite_style(&c, urlprefix)) {
         Err(e) => {
-            println!("Error when reading tilejson file to serve up: {:?}", e);
+            println!("Error with style {:?}: {}", name, e);
             *res.status_mut() = hyper::status::StatusCode::InternalServerError;
         },
-        Ok(json) => {
-            res.send(json.as_bytes()).unwrap_or_else(|e| {
-                println!("Error when trying to send tilejson to client: {:?}", e);
-            });
-        }
+        Ok(style) => {
+            send_json(res, &style, callback);
+        },
+    }
+}
+
+/// Serve a sprite's JSON index or PNG image
+fn sprite_handler(res: Response, sprites_dir: &Path, filename: &str) {
+    let mime = if filename.ends_with(".png") {
+        Mime(TopLevel::Image, SubLevel::Png, vec![])
+    } else {
+        Mime(TopLevel::Application, SubLevel::Json, vec![(Attr::Charset, Value::Utf8)])
     };
+    send_file(res, read_file(&sprites_dir.join(filename)), mime);
 }
 
-fn parse_out_upstreams(args: Option<clap::Values>) -> HashMap<String, String> {
-    let mut upstreams: HashMap<String, String> = HashMap::new();
+/// Serve glyphs from the first font in the stack which has this range. Glyphs are stored like
+/// `FONTS_DIR/Open Sans Regular/0-255.pbf`.
+fn glyphs_handler(res: Response, glyphs_dir: &Path, fontstack: &[String], range: &str) {
+    let contents = fontstack.iter().filter_map(|font| read_file(&glyphs_dir.join(font).join(format!("{}.pbf", range)))).next();
+    send_file(res, contents, Mime(TopLevel::Application, SubLevel::Ext("x-protobuf".to_owned()), vec![]));
+}
+
+/// Send some JSON, wrapped in a call to `callback` (JSONP) if given
+fn send_json(mut res: Response, json: &str, callback: &Option<String>) {
+    res.headers_mut().set(CacheControl(vec![CacheDirective::Public, CacheDirective::MaxAge(JSON_MAX_AGE_SECS)]));
+    let body = match *callback {
+        None => {
+            res.headers_mut().set(ContentType(Mime(TopLevel::Application, SubLevel::Json, vec![(Attr::Charset, Value::Utf8)])));
+            json.to_string()
+        },
+        Some(ref callback) => {
+            res.headers_mut().set(ContentType(Mime(TopLevel::Application, SubLevel::Javascript, vec![(Attr::Charset, Value::Utf8)])));
+            format!("{}({});", callback, json)
+        },
+    };
+    res.send(body.as_bytes()).unwrap_or_else(|e| {
+        println!("Error when trying to send json to client: {:?}", e);
+    });
+}
+
+/// Turn a list of `PREFIX VALUE PREFIX VALUE ...` command line args into pairs
+fn parse_out_prefix_pairs(args: Option<clap::Values>) -> Vec<(String, String)> {
+    let mut pairs: Vec<(String, String)> = Vec::new();
     if let Some(raw) = args {
         let raw: Vec<_> = raw.collect();
         if raw.len() > 0 {
@@ -261,10 +975,209 @@ fn parse_out_upstreams(args: Option<clap::Values>) -> HashMap<String, String> {
                 // this is like the past
                 // currently unstable step_by on range will help
                 if i >= raw.len() { break; }
-                upstreams.insert(raw[i].to_string(), raw[i+1].to_string());
+                pairs.push((raw[i].to_string(), raw[i+1].to_string()));
                 i += 2;
             }
         }
     }
+    pairs
+}
+
+/// Construct the upstreams from the `--upstream`, `--upstream-tilejson` & `--upstream-header`
+/// (etc.) options. Exits if they are invalid.
+fn parse_out_upstreams(options: &ArgMatches) -> HashMap<String, Upstream> {
+    let tilejson_urls: HashMap<String, String> = parse_out_prefix_pairs(options.values_of("upstream_tilejson_url")).into_iter().collect();
+    let mut upstreams: HashMap<String, Upstream> = HashMap::new();
+    for (prefix, url) in parse_out_prefix_pairs(options.values_of("upstream_url")) {
+        // The same prefix more than once means more mirrors. --upstream-tilejson is for the first
+        if upstreams.contains_key(&prefix) {
+            upstreams.get_mut(&prefix).unwrap().add_mirror(&url, None);
+        } else {
+            let upstream = Upstream::new(&url, tilejson_urls.get(&prefix).map(|s| s.as_str()));
+            upstreams.insert(prefix, upstream);
+        }
+    }
+
+    let prefix_options = [("upstream_header", "--upstream-header"), ("upstream_basic_auth", "--upstream-basic-auth"), ("upstream_bearer_token", "--upstream-bearer-token"), ("max_age", "--max-age")];
+    for &(option_name, flag) in prefix_options.iter() {
+        for (prefix, value) in parse_out_prefix_pairs(options.values_of(option_name)) {
+            let result = match upstreams.get_mut(&prefix) {
+                None => Err(format!("There is no --upstream for prefix {:?}", prefix)),
+                Some(upstream) => match option_name {
+                    "upstream_header" => upstream.add_header(&value),
+                    "upstream_basic_auth" => upstream.set_basic_auth(&value),
+                    "upstream_bearer_token" => upstream.set_bearer_token(&value),
+                    _ => upstream.set_max_age(&value),
+                },
+            };
+            if let Err(e) = result {
+                println!("Invalid {} for prefix {:?}: {}", flag, prefix, e);
+                ::std::process::exit(1);
+            }
+        }
+    }
+
     upstreams
 }
+
+mod test {
+    #[test]
+    fn test_catalog_contents() {
+        use super::{catalog_contents, has_tilejson, TileJsonCache};
+        use aliases::Aliases;
+        use rustc_serialize::json::Json;
+        use std::fs;
+        use std::env;
+        use std::path::Path;
+
+        let dir = env::temp_dir().join(format!("iompair-test-catalog-{}", ::std::process::id()));
+        let path = dir.to_str().unwrap();
+        let write = |file: &str, contents: &str| {
+            let file = dir.join(file);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, contents).unwrap();
+        };
+        write("land/index.json", r#"{"name": "Land", "minzoom": 2, "maxzoom": 12, "bounds": [-10, -5, 10, 5], "vector_layers": [{"id": "water"}]}"#);
+        write("points/metadata.json", r#"{"maxzoom": 14, "vector_layers": [{"id": "pois"}]}"#);
+        // Not tilesets: no TileJSON, not a valid prefix, a file, and the alias's own directory
+        fs::create_dir_all(dir.join("empty")).unwrap();
+        write("bad__name/index.json", "{}");
+        write("notes.txt", "");
+        write("basemap/index.json", r#"{"name": "Old basemap"}"#);
+        let aliases = Aliases::parse(r#"{"basemap": {"prefixes": ["land", "points"], "name": "Basemap"}}"#).unwrap();
+
+        // No TileJSON in the root, so /index.json is the catalogue too
+        assert!(! has_tilejson(Path::new(path)));
+
+        let catalog = Json::from_str(&catalog_contents(path, "http://localhost:9000/", 14, 14, &aliases, &TileJsonCache::new()).unwrap()).unwrap();
+        let tilesets = catalog.find("tilesets").unwrap().as_array().unwrap();
+        let field = |i: usize, key: &str| tilesets[i].find(key).cloned();
+        let ids: Vec<&str> = tilesets.iter().map(|t| t.find("id").unwrap().as_string().unwrap()).collect();
+        assert_eq!(ids, vec!["land", "points", "basemap"]);
+
+        assert_eq!(field(0, "name"), Some(Json::String("Land".to_string())));
+        assert_eq!(field(0, "tilejson"), Some(Json::String("http://localhost:9000/land/index.json".to_string())));
+        assert_eq!(field(0, "minzoom"), Some(Json::U64(2)));
+        assert_eq!(field(0, "maxzoom"), Some(Json::U64(12)));
+        assert_eq!(field(0, "layers"), Some(Json::Array(vec![Json::String("water".to_string())])));
+        assert!(field(0, "bounds").is_some());
+
+        // Defaults for what metadata.json doesn't have
+        assert_eq!(field(1, "name"), Some(Json::String("points".to_string())));
+        assert_eq!(field(1, "minzoom"), Some(Json::U64(0)));
+        assert_eq!(field(1, "bounds"), None);
+
+        // The alias is the merge of its prefixes, with its own name
+        assert_eq!(field(2, "name"), Some(Json::String("Basemap".to_string())));
+        assert_eq!(field(2, "tilejson"), Some(Json::String("http://localhost:9000/basemap/index.json".to_string())));
+        assert_eq!(field(2, "minzoom"), Some(Json::U64(2)));
+        assert_eq!(field(2, "maxzoom"), Some(Json::U64(14)));
+        assert_eq!(field(2, "layers"), Some(Json::Array(vec![Json::String("water".to_string()), Json::String("pois".to_string())])));
+
+        // With a root TileJSON, /index.json is that instead
+        write("index.json", "{}");
+        assert!(has_tilejson(Path::new(path)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_tilejsons() {
+        use super::{merge_tilejsons, layer_renames};
+        use rustc_serialize::json::Json;
+
+        let tilejson = |prefix: &str, json: &str| (prefix.to_string(), Json::from_str(json).unwrap().as_object().unwrap().clone());
+        let tilejsons = vec![
+            tilejson("land", r#"{"name": "Land", "bounds": [-10, -5, 10, 5], "minzoom": 2, "maxzoom": 12, "attribution": "A", "vector_layers": [{"id": "water"}, {"id": "land"}]}"#),
+            tilejson("points", r#"{"bounds": [0, 0, 20, 20], "minzoom": 0, "maxzoom": 14, "attribution": "B", "description": "Points", "vector_layers": [{"id": "water"}, {"id": "pois"}]}"#),
+            tilejson("roads", r#"{"attribution": "A"}"#),
+        ];
+        let renames = layer_renames(&tilejsons);
+        assert!(renames[0].is_empty());
+        assert_eq!(renames[1].get("water"), Some(&"points_water".to_string()));
+
+        let merged = Json::Object(merge_tilejsons(&tilejsons, &renames).unwrap());
+        assert_eq!(merged.find("name"), Some(&Json::String("Land".to_string())));
+        assert_eq!(merged.find("bounds"), Some(&Json::Array(vec![Json::F64(-10.), Json::F64(-5.), Json::F64(20.), Json::F64(20.)])));
+        assert_eq!(merged.find("minzoom"), Some(&Json::U64(0)));
+        assert_eq!(merged.find("maxzoom"), Some(&Json::U64(14)));
+        assert_eq!(merged.find("attribution"), Some(&Json::String("A; B".to_string())));
+        assert_eq!(merged.find("description"), Some(&Json::String("Points".to_string())));
+        let ids: Vec<&str> = merged.find("vector_layers").unwrap().as_array().unwrap().iter().map(|l| l.find("id").unwrap().as_string().unwrap()).collect();
+        assert_eq!(ids, vec!["water", "land", "points_water", "pois"]);
+
+        // Errors, not panics
+        assert!(merge_tilejsons(&[], &[]).is_err());
+        let bad = vec![tilejson("land", r#"{"vector_layers": {"id": "water"}}"#)];
+        assert!(merge_tilejsons(&bad, &layer_renames(&bad)).is_err());
+        let bad = vec![tilejson("land", r#"{"bounds": [1, 2, 3]}"#)];
+        assert!(merge_tilejsons(&bad, &layer_renames(&bad)).is_err());
+    }
+
+    #[test]
+    fn test_file_cache() {
+        use super::FileCache;
+        use std::fs;
+        use std::env;
+        use std::io::Write;
+        use std::cell::Cell;
+        use filetime::{FileTime, set_file_times};
+
+        let dir = env::temp_dir().join(format!("iompair-test-file-cache-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("index.json");
+        let files = vec![file.clone(), dir.join("metadata.json")];
+        fs::File::create(&file).unwrap().write_all(b"{}").unwrap();
+
+        let cache = FileCache::new();
+        let made = Cell::new(0);
+        let make = || -> Result<usize, ()> { made.set(made.get() + 1); Ok(made.get()) };
+        assert_eq!(cache.get("a", &files, &make), Ok(1));
+        assert_eq!(cache.get("a", &files, &make), Ok(1));
+        assert_eq!(cache.get("b", &files, &make), Ok(2));
+
+        // Changing a file makes it again
+        let long_ago = FileTime::from_seconds_since_1970(1_000_000_000, 0);
+        set_file_times(&file, long_ago, long_ago).unwrap();
+        assert_eq!(cache.get("a", &files, &make), Ok(3));
+        assert_eq!(cache.get("a", &files, &make), Ok(3));
+
+        // So does another file appearing
+        fs::File::create(&files[1]).unwrap();
+        assert_eq!(cache.get("a", &files, &make), Ok(4));
+
+        // Errors aren't cached
+        assert_eq!(cache.get("c", &files, || -> Result<usize, ()> { Err(()) }), Err(()));
+        assert_eq!(cache.get("c", &files, &make), Ok(5));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_background_refresher() {
+        use super::BackgroundRefresher;
+        use std::path::Path;
+        use std::thread;
+
+        let refresher = BackgroundRefresher::new(2);
+        let a = refresher.start(Path::new("/tiles/1/0/0.pbf")).unwrap();
+        // Only refreshed once at a time
+        assert!(refresher.start(Path::new("/tiles/1/0/0.pbf")).is_none());
+        let b = refresher.start(Path::new("/tiles/1/0/1.pbf")).unwrap();
+        // Too many at once
+        assert!(refresher.start(Path::new("/tiles/1/1/0.pbf")).is_none());
+
+        drop(a);
+        let a = refresher.start(Path::new("/tiles/1/0/0.pbf"));
+        assert!(a.is_some());
+        drop(a);
+
+        // A refresh which panics is still finished
+        let result = thread::spawn(move || {
+            let _b = b;
+            panic!("refresh failed");
+        }).join();
+        assert!(result.is_err());
+        assert!(refresher.start(Path::new("/tiles/1/0/1.pbf")).is_some());
+    }
+}
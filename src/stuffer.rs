@@ -4,6 +4,7 @@ extern crate slippy_map_tiles;
 extern crate simple_parallel;
 extern crate iter_progress;
 extern crate chrono;
+extern crate rustc_serialize;
 
 use std::path::Path;
 use std::os::unix::fs::MetadataExt;
@@ -12,19 +13,17 @@ use clap::ArgMatches;
 use slippy_map_tiles::Tile;
 use iter_progress::ProgressableIter;
 use chrono::{DateTime, FixedOffset};
+use rustc_serialize::json;
 
-use utils::{download_url_and_save_to_file, IompairError, DirectoryLayout};
+use upstream::Upstream;
+use utils::{download_url, save_to_file, download_url_and_save_to_file, HttpOptions, RefreshStats, IompairError, DirectoryLayout};
 
-fn dl_tile(tile: Tile, path: &str, path_format: DirectoryLayout, upstream_url: &str, always_download: bool, files_older_than: &Option<DateTime<FixedOffset>>) -> Result<(), IompairError> {
+fn dl_tile(tile: Tile, path: &str, path_format: DirectoryLayout, upstream: &Upstream, always_download: bool, files_older_than: &Option<DateTime<FixedOffset>>, http: &HttpOptions, stats: &RefreshStats) -> Result<(), IompairError> {
     let x = tile.x();
     let y = tile.y();
     let z = tile.zoom();
 
-    let this_path = format!("{}/{}", path, match path_format {
-        DirectoryLayout::TCPath => tile.tc_path("pbf"),
-        DirectoryLayout::TSPath => tile.ts_path("pbf"),
-        DirectoryLayout::ZXYPath => tile.zxy_path("pbf"),
-    });
+    let this_path = format!("{}/{}", path, path_format.tile_path(&tile, "pbf"));
     let this_path = Path::new(&this_path);
 
     let should_download = if ! this_path.exists() {
@@ -44,44 +43,76 @@ fn dl_tile(tile: Tile, path: &str, path_format: DirectoryLayout, upstream_url: &
         }
     };
 
-    if should_download {
-        try!(download_url_and_save_to_file(&format!("{}/{}/{}/{}.pbf", upstream_url, z, x, y), this_path));
+    if should_download && upstream.covers(z, x, y) {
+        let url = upstream.tile_url(z, x, y);
+        let result = download_url_and_save_to_file(&url, this_path, 10, http, upstream.headers_for(&url));
+        stats.record(z, x, y, &result);
+        try!(result);
     }
 
     Ok(())
 }
 
-fn dl_tilejson(path: &str, upstream_url: &str) -> Result<(), IompairError> {
-    try!(download_url_and_save_to_file(&format!("{}/index.json", upstream_url), Path::new(&format!("{}/index.json", path))));
-    Ok(())
+fn dl_tilejson(path: &str, tilejson_url: &str, http: &HttpOptions, headers: &[(String, String)]) -> Result<Vec<u8>, IompairError> {
+    let contents = try!(download_url(tilejson_url, 10, http, headers));
+    try!(save_to_file(Path::new(&format!("{}/index.json", path)), &contents));
+    Ok(contents)
 }
 
 
 pub fn stuffer(options: &ArgMatches) {
 
-    let upstream_url = options.value_of("upstream_url").unwrap().to_string();
+    let mut upstream = Upstream::new(options.value_of("upstream_url").unwrap(), options.value_of("upstream_tilejson_url"));
+    upstream.add_headers_from_options(options);
     let path = options.value_of("tc_path").or(options.value_of("ts_path")).or(options.value_of("zxy_path")).unwrap().to_string();
     let path_format = if options.is_present("tc_path") { DirectoryLayout::TCPath } else if options.is_present("ts_path") { DirectoryLayout::TSPath } else if options.is_present("zxy_path") { DirectoryLayout::ZXYPath } else { unreachable!() };
     let threads = options.value_of("threads").unwrap().parse().unwrap();
-    let max_zoom = options.value_of("max-zoom").unwrap().parse().unwrap();
-    let min_zoom: u8 = options.value_of("min-zoom").unwrap().parse().unwrap();
 
     let always_download = options.is_present("always-download");
     let files_older_than: Option<DateTime<FixedOffset>> = options.value_of("files-older-than").and_then(|t| { DateTime::parse_from_rfc3339(t).ok() });
 
-    let top = options.value_of("top").unwrap().parse().unwrap();
-    let bottom = options.value_of("bottom").unwrap().parse().unwrap();
-    let left = options.value_of("left").unwrap().parse().unwrap();
-    let right = options.value_of("right").unwrap().parse().unwrap();
-
+    let http = HttpOptions::from_options(options);
+    let stats = RefreshStats::new(options.value_of("changed_tiles_list"), None).unwrap_or_else(|e| {
+        println!("Couldn't open the changed tiles list: {:?}", e);
+        ::std::process::exit(1);
+    });
 
     // Download the tilejson file and save it for later.
-    dl_tilejson(&path, &upstream_url).unwrap_or_else(|e| {
-        println!("Error occured when downloading tilejson: {:?}", e);
-        println!("Aborting");
-        return;
-    });
-    println!("Downloaded TileJSON");
+    match upstream.tilejson_url().map(|u| u.to_string()) {
+        None => {
+            println!("No TileJSON URL for this upstream, not downloading TileJSON. Use --upstream-tilejson to set one");
+        },
+        Some(tilejson_url) => {
+            match dl_tilejson(&path, &tilejson_url, &http, upstream.headers_for(&tilejson_url)) {
+                Err(e) => {
+                    println!("Error occured when downloading tilejson: {:?}", e);
+                    println!("Aborting");
+                    return;
+                },
+                Ok(bytes) => {
+                    match String::from_utf8(bytes).ok().and_then(|s| json::Json::from_str(&s).ok()) {
+                        Some(tilejson) => upstream.update_from_tilejson(&tilejson),
+                        None => println!("Upstream TileJSON is not valid JSON, ignoring it"),
+                    }
+                },
+            }
+            println!("Downloaded TileJSON");
+        },
+    }
+
+    // Zooms & bbox default to what's in the TileJSON (or everything if it's not there)
+    let max_zoom: u8 = options.value_of("max-zoom").map(|z| z.parse().unwrap()).or(upstream.maxzoom()).unwrap_or(14);
+    let min_zoom: u8 = options.value_of("min-zoom").map(|z| z.parse().unwrap()).or(upstream.minzoom()).unwrap_or(0);
+
+    // Bounds across the antimeridian (west > east) become all longitudes, and `covers` skips the
+    // tiles in between
+    let (default_left, default_bottom, default_right, default_top) = upstream.bounds()
+        .map(|(w, s, e, n)| if w > e { (-180., s as f32, 180., n as f32) } else { (w as f32, s as f32, e as f32, n as f32) })
+        .unwrap_or((-180., -90., 180., 90.));
+    let top: f32 = options.value_of("top").map(|t| t.parse().unwrap()).unwrap_or(default_top);
+    let bottom: f32 = options.value_of("bottom").map(|t| t.parse().unwrap()).unwrap_or(default_bottom);
+    let left: f32 = options.value_of("left").map(|t| t.parse().unwrap()).unwrap_or(default_left);
+    let right: f32 = options.value_of("right").map(|t| t.parse().unwrap()).unwrap_or(default_right);
 
     println!("Starting {} threads", threads);
     let mut pool = simple_parallel::Pool::new(threads);
@@ -93,7 +124,7 @@ pub fn stuffer(options: &ArgMatches) {
         let iter = Box::new(Tile::all_to_zoom(max_zoom).filter(|&t| { t.zoom() >= min_zoom }));
         pool.for_(iter.progress(), |(state, tile)| {
             state.print_every_n_sec(5., format!("{} done ({}/sec), tile {:?}       \r", state.num_done(), state.rate(), tile));
-            dl_tile(tile, &path, path_format, &upstream_url, always_download, &files_older_than).unwrap_or_else(|e| {
+            dl_tile(tile, &path, path_format, &upstream, always_download, &files_older_than, &http, &stats).unwrap_or_else(|e| {
                 println!("Error occured when downloading tile {:?}: {:?}", tile, e);
             });
         });
@@ -109,7 +140,7 @@ pub fn stuffer(options: &ArgMatches) {
                 pool.for_(iter.progress(), |(state, tile)| {
                     state.print_every_n_sec(1., format!("{} done ({}/sec), tile {:?}       \r", state.num_done(), state.rate(), tile));
                     //state.print_every_sec(100., format!("{} done ({}/sec), tile {:?}       \r", state.num_done(), state.rate(), tile));
-                    dl_tile(tile, &path, path_format, &upstream_url, always_download, &files_older_than).unwrap_or_else(|e| {
+                    dl_tile(tile, &path, path_format, &upstream, always_download, &files_older_than, &http, &stats).unwrap_or_else(|e| {
                         println!("Error occured when downloading tile {:?}: {:?}", tile, e);
                     });
                 });
@@ -118,4 +149,5 @@ pub fn stuffer(options: &ArgMatches) {
     }
 
     print!("\n");
+    println!("{}", stats);
 }
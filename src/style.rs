@@ -0,0 +1,91 @@
+extern crate rustc_serialize;
+
+use rustc_serialize::json::{self, Json};
+
+/// URLs in style files starting with this are rewritten to point at this server
+const LOCAL_SCHEME: &'static str = "iompair://";
+
+/// Rewrite a local URL (`iompair://...`) to be under `urlprefix`. Other URLs are left alone.
+fn rewrite_url(url: &str, urlprefix: &str, is_source: bool) -> Option<String> {
+    if ! url.starts_with(LOCAL_SCHEME) {
+        return None;
+    }
+    let rest = url[LOCAL_SCHEME.len()..].trim_left_matches('/');
+    if is_source && ! rest.ends_with(".json") {
+        // A prefix (or alias), so use its TileJSON
+        Some(format!("{}{}/index.json", urlprefix, rest.trim_right_matches('/')))
+    } else {
+        Some(format!("{}{}", urlprefix, rest))
+    }
+}
+
+/// Rewrite a MapLibre/Mapbox GL style, so that the `url` & `tiles` of its `sources`, and its
+/// `sprite` & `glyphs`, point at this server (`urlprefix`) if they start with `iompair://`. e.g.
+/// a source with `"url": "iompair://land__points"` gets the TileJSON for `land__points`, and
+/// `"glyphs": "iompair://fonts/{fontstack}/{range}.pbf"` gets the fonts from this server.
+pub fn rewrite_style(style: &str, urlprefix: &str) -> Result<String, String> {
+    let style = try!(Json::from_str(style).map_err(|e| format!("Invalid JSON: {}", e)));
+    let mut style = try!(style.as_object().cloned().ok_or("Style isn't a JSON object".to_string()));
+
+    if let Some(&mut Json::Object(ref mut sources)) = style.get_mut("sources") {
+        for (_, source) in sources.iter_mut() {
+            let source = match *source {
+                Json::Object(ref mut source) => source,
+                _ => { continue; },
+            };
+            let new_url = source.get("url").and_then(|u| u.as_string()).and_then(|u| rewrite_url(u, urlprefix, true));
+            if let Some(new_url) = new_url {
+                source.insert("url".to_owned(), Json::String(new_url));
+            }
+            if let Some(&mut Json::Array(ref mut tiles)) = source.get_mut("tiles") {
+                for tile_url in tiles.iter_mut() {
+                    let new_url = tile_url.as_string().and_then(|u| rewrite_url(u, urlprefix, false));
+                    if let Some(new_url) = new_url {
+                        *tile_url = Json::String(new_url);
+                    }
+                }
+            }
+        }
+    }
+
+    for key in &["sprite", "glyphs"] {
+        let new_url = style.get(*key).and_then(|u| u.as_string()).and_then(|u| rewrite_url(u, urlprefix, false));
+        if let Some(new_url) = new_url {
+            style.insert(key.to_string(), Json::String(new_url));
+        }
+    }
+
+    json::encode(&style).map_err(|e| e.to_string())
+}
+
+mod test {
+    #[test]
+    fn test_rewrite_style() {
+        use super::rewrite_style;
+        use rustc_serialize::json::Json;
+
+        let style = r#"{
+            "version": 8,
+            "sprite": "iompair://sprites/basic",
+            "glyphs": "iompair://fonts/{fontstack}/{range}.pbf",
+            "sources": {
+                "base": {"type": "vector", "url": "iompair://land__points"},
+                "other": {"type": "vector", "url": "https://example.com/tiles.json"},
+                "raw": {"type": "vector", "tiles": ["iompair://land/{z}/{x}/{y}.pbf?layers=water"]}
+            },
+            "layers": [{"id": "background", "type": "background"}]
+        }"#;
+        let rewritten = Json::from_str(&rewrite_style(style, "http://example.org/tiles/").unwrap()).unwrap();
+        let string = |path: &[&str]| rewritten.find_path(path).and_then(|s| s.as_string()).map(|s| s.to_string());
+
+        assert_eq!(string(&["sprite"]), Some("http://example.org/tiles/sprites/basic".to_string()));
+        assert_eq!(string(&["glyphs"]), Some("http://example.org/tiles/fonts/{fontstack}/{range}.pbf".to_string()));
+        assert_eq!(string(&["sources", "base", "url"]), Some("http://example.org/tiles/land__points/index.json".to_string()));
+        assert_eq!(string(&["sources", "other", "url"]), Some("https://example.com/tiles.json".to_string()));
+        assert_eq!(rewritten.find_path(&["sources", "raw", "tiles"]).unwrap()[0], Json::String("http://example.org/tiles/land/{z}/{x}/{y}.pbf?layers=water".to_string()));
+        assert_eq!(rewritten.find("layers").unwrap().as_array().unwrap().len(), 1);
+
+        assert!(rewrite_style("[]", "http://example.org/").is_err());
+        assert!(rewrite_style("{", "http://example.org/").is_err());
+    }
+}
@@ -0,0 +1,711 @@
+extern crate rustc_serialize;
+extern crate clap;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::f64::consts::PI;
+use std::fmt;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+use rustc_serialize::json::Json;
+use rustc_serialize::base64::{ToBase64, STANDARD};
+
+use clap::ArgMatches;
+
+use watch::backoff;
+use utils::{IompairError, url_host};
+
+/// The subdomains used for `{s}` if none are given
+const DEFAULT_SUBDOMAINS: [&'static str; 3] = ["a", "b", "c"];
+
+/// A URL template for downloading tiles from an upstream.
+///
+/// Supports the placeholders `{z}`, `{x}`, `{y}`, `{-y}` (TMS y), `{quadkey}` and `{s}` (which
+/// rotates through the subdomains `a`, `b` & `c`, or the ones listed like `{s:1,2,3}`).
+///
+/// For backwards compatibility, a URL without any placeholders is treated as a base URL, and tiles
+/// are downloaded from `$URL/{z}/{x}/{y}.pbf`
+#[derive(Debug)]
+pub struct TileURLTemplate {
+    template: String,
+    subdomains: Vec<String>,
+    next_subdomain: AtomicUsize,
+}
+
+impl TileURLTemplate {
+    /// Parse a template string (or legacy base URL)
+    pub fn parse(s: &str) -> Self {
+        let mut template = if is_template(s) { s.to_string() } else { format!("{}/{{z}}/{{x}}/{{y}}.pbf", s) };
+        let mut subdomains: Vec<String> = DEFAULT_SUBDOMAINS.iter().map(|s| s.to_string()).collect();
+
+        // Custom subdomains like {s:1,2,3}
+        if let Some(start) = template.find("{s:") {
+            if let Some(len) = template[start..].find('}') {
+                let end = start + len;
+                subdomains = template[start+3..end].split(',').map(|s| s.trim().to_string()).filter(|s| s != "").collect();
+                template = format!("{}{{s}}{}", &template[..start], &template[end+1..]);
+            }
+        }
+
+        TileURLTemplate{ template: template, subdomains: subdomains, next_subdomain: AtomicUsize::new(0) }
+    }
+
+    /// Swap `{y}` & `{-y}`, for the `tiles` of a TileJSON with `"scheme": "tms"`, where `{y}` is
+    /// counted from the bottom
+    fn flip_y(mut self) -> Self {
+        self.template = self.template.replace("{-y}", "{tms-y}").replace("{y}", "{-y}").replace("{tms-y}", "{y}");
+        self
+    }
+
+    /// The URL for this tile.
+    pub fn tile_url(&self, z: u8, x: u32, y: u32) -> String {
+        let mut url = self.template.replace("{z}", &z.to_string())
+            .replace("{x}", &x.to_string())
+            .replace("{-y}", &tms_y(z, y).to_string())
+            .replace("{y}", &y.to_string());
+
+        if url.contains("{quadkey}") {
+            url = url.replace("{quadkey}", &quadkey(z, x, y));
+        }
+        if url.contains("{s}") && self.subdomains.len() > 0 {
+            let idx = self.next_subdomain.fetch_add(1, Ordering::Relaxed) % self.subdomains.len();
+            url = url.replace("{s}", &self.subdomains[idx]);
+        }
+
+        url
+    }
+
+    /// The hosts that tiles from this template are on (one for each subdomain, if it has `{s}`)
+    fn hosts(&self) -> Vec<String> {
+        let host = url_host(&self.template).to_lowercase();
+        if host.contains("{s}") {
+            self.subdomains.iter().map(|s| host.replace("{s}", &s.to_lowercase())).collect()
+        } else {
+            vec![host]
+        }
+    }
+}
+
+/// Does this look like a URL template (rather than a base URL)?
+fn is_template(s: &str) -> bool {
+    s.contains("{z}") || s.contains("{quadkey}")
+}
+
+/// The y coordinate in the TMS scheme (where y goes from the south)
+fn tms_y(z: u8, y: u32) -> u32 {
+    ((1u64 << z) - 1 - y as u64) as u32
+}
+
+/// Bing Maps style quadkey for this tile
+pub fn quadkey(z: u8, x: u32, y: u32) -> String {
+    let mut key = String::with_capacity(z as usize);
+    for i in (1..z+1).rev() {
+        let mask = 1 << (i - 1);
+        let mut digit = 0;
+        if x & mask != 0 { digit += 1; }
+        if y & mask != 0 { digit += 2; }
+        key.push_str(&digit.to_string());
+    }
+    key
+}
+
+/// The lon/lat bounds of a tile, as (west, south, east, north)
+pub fn tile_bounds(z: u8, x: u32, y: u32) -> (f64, f64, f64, f64) {
+    let n = (1u64 << z) as f64;
+    let lon = |x: f64| x / n * 360. - 180.;
+    let lat = |y: f64| (PI * (1. - 2. * y / n)).sinh().atan().to_degrees();
+    (lon(x as f64), lat(y as f64 + 1.), lon(x as f64 + 1.), lat(y as f64))
+}
+
+/// Replace `{env:NAME}` with the value of the environment variable `NAME`, and `{file:PATH}` with
+/// the contents of the file `PATH` (with leading & trailing whitespace removed). This allows
+/// secrets to be kept off the command line.
+pub fn resolve_secrets(value: &str) -> Result<String, String> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    loop {
+        let (start, is_env) = match (rest.find("{env:"), rest.find("{file:")) {
+            (None, None) => break,
+            (Some(e), None) => (e, true),
+            (None, Some(f)) => (f, false),
+            (Some(e), Some(f)) => if e < f { (e, true) } else { (f, false) },
+        };
+        let end = try!(rest[start..].find('}').map(|e| start + e).ok_or(format!("Unclosed {{ in {:?}", value)));
+        result.push_str(&rest[..start]);
+
+        if is_env {
+            let name = &rest[start+5..end];
+            let env_value = try!(env::var(name).map_err(|e| format!("Environment variable {:?}: {}", name, e)));
+            result.push_str(&env_value);
+        } else {
+            let path = &rest[start+6..end];
+            let mut contents = String::new();
+            try!(File::open(path).and_then(|mut f| f.read_to_string(&mut contents)).map_err(|e| format!("File {:?}: {}", path, e)));
+            result.push_str(contents.trim());
+        }
+
+        rest = &rest[end+1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// How long a mirror is avoided after it first fails. This doubles with every failure in a row
+const MIRROR_BACKOFF_SECS: u64 = 5;
+
+/// One of the servers which an upstream's tiles can be downloaded from. All the mirrors of an
+/// upstream should have the same tiles.
+#[derive(Debug)]
+struct Mirror {
+    /// Can be changed (from the mirror's TileJSON) while serving, when a mirror starts working
+    tiles: RwLock<Vec<TileURLTemplate>>,
+    next_template: AtomicUsize,
+    is_template: bool,
+    tilejson_url: Option<String>,
+    /// The hosts of the URLs this mirror was given with, which are the only ones that the
+    /// upstream's headers are sent to
+    hosts: Vec<String>,
+    /// How many requests in a row have failed
+    consecutive_failures: AtomicUsize,
+    /// Other mirrors are preferred until this time, after a failure
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl Mirror {
+    fn new(url: &str, tilejson_url: Option<&str>) -> Self {
+        let tilejson_url = match tilejson_url {
+            Some(t) => Some(t.to_string()),
+            None if ! is_template(url) => Some(format!("{}/index.json", url)),
+            None => None,
+        };
+        let template = TileURLTemplate::parse(url);
+        let mut hosts = template.hosts();
+        if let Some(ref tilejson_url) = tilejson_url {
+            hosts.push(url_host(tilejson_url).to_lowercase());
+        }
+        Mirror{
+            tiles: RwLock::new(vec![template]), next_template: AtomicUsize::new(0),
+            is_template: is_template(url), tilejson_url: tilejson_url, hosts: hosts,
+            consecutive_failures: AtomicUsize::new(0), down_until: Mutex::new(None),
+        }
+    }
+
+    fn tile_url(&self, z: u8, x: u32, y: u32) -> String {
+        let tiles = self.tiles.read().unwrap();
+        let idx = self.next_template.fetch_add(1, Ordering::Relaxed) % tiles.len();
+        tiles[idx].tile_url(z, x, y)
+    }
+
+    fn is_up(&self) -> bool {
+        match *self.down_until.lock().unwrap() {
+            None => true,
+            Some(down_until) => Instant::now() >= down_until,
+        }
+    }
+
+    fn mark_up(&self) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        *self.down_until.lock().unwrap() = None;
+    }
+
+    fn mark_down(&self) {
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        let wait = backoff(Duration::new(MIRROR_BACKOFF_SECS, 0), failures as u32);
+        *self.down_until.lock().unwrap() = Some(Instant::now() + wait);
+    }
+}
+
+/// Which tiles an upstream has, according to its TileJSON
+#[derive(Debug, Clone, Copy, Default)]
+struct Coverage {
+    minzoom: Option<u8>,
+    maxzoom: Option<u8>,
+    bounds: Option<(f64, f64, f64, f64)>,
+    /// Whether these have been taken from a mirror's TileJSON yet
+    from_mirror: bool,
+}
+
+impl Coverage {
+    fn from_tilejson(tilejson: &Json) -> Self {
+        let as_zoom = |key: &str| tilejson.find(key).and_then(|z| z.as_u64()).and_then(|z| if z <= 30 { Some(z as u8) } else { None });
+        let bounds = tilejson.find("bounds").and_then(|b| b.as_array()).and_then(|b| {
+            let b: Vec<f64> = b.iter().filter_map(|x| x.as_f64()).collect();
+            if b.len() == 4 { Some((b[0], b[1], b[2], b[3])) } else { None }
+        });
+        Coverage{ minzoom: as_zoom("minzoom"), maxzoom: as_zoom("maxzoom"), bounds: bounds, from_mirror: false }
+    }
+}
+
+/// An upstream source of vector tiles, which can have many mirrors
+pub struct Upstream {
+    mirrors: Vec<Mirror>,
+    next_mirror: AtomicUsize,
+    coverage: RwLock<Coverage>,
+    headers: Vec<(String, String)>,
+    max_age: Option<Duration>,
+}
+
+// Manual Debug so that header values (which can be secrets) aren't printed
+impl fmt::Debug for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let header_names: Vec<&str> = self.headers.iter().map(|&(ref name, _)| name.as_str()).collect();
+        let tiles: Vec<Vec<String>> = self.mirrors.iter().map(|m| m.tiles.read().unwrap().iter().map(|t| t.template.clone()).collect()).collect();
+        let tilejson_urls: Vec<&Option<String>> = self.mirrors.iter().map(|m| &m.tilejson_url).collect();
+        write!(f, "Upstream {{ tiles: {:?}, tilejson_url: {:?}, headers: {:?} }}", tiles, tilejson_urls, header_names)
+    }
+}
+
+impl Upstream {
+    /// Construct a new Upstream from a URL (template) and optional TileJSON URL. If no TileJSON
+    /// URL is given, and `url` is a base URL, then the TileJSON is presumed to be at
+    /// `$URL/index.json`.
+    pub fn new(url: &str, tilejson_url: Option<&str>) -> Self {
+        Upstream{
+            mirrors: vec![Mirror::new(url, tilejson_url)], next_mirror: AtomicUsize::new(0),
+            coverage: RwLock::new(Coverage::default()), headers: Vec::new(), max_age: None,
+        }
+    }
+
+    /// Add another mirror, which has the same tiles. Requests are spread over all the mirrors
+    /// which are working.
+    pub fn add_mirror(&mut self, url: &str, tilejson_url: Option<&str>) {
+        self.mirrors.push(Mirror::new(url, tilejson_url));
+    }
+
+    /// How many mirrors this upstream has
+    pub fn num_mirrors(&self) -> usize {
+        self.mirrors.len()
+    }
+
+    /// How many times to try downloading from each mirror. With only one mirror we try hard, but
+    /// with many, it's better to fail over to the next one quickly.
+    pub fn tries_per_mirror(&self) -> u8 {
+        if self.mirrors.len() > 1 { 2 } else { 10 }
+    }
+
+    /// Add a HTTP header (like `Name: value`) to send with every request to this upstream.
+    /// `{env:NAME}`/`{file:PATH}` in the value are replaced.
+    pub fn add_header(&mut self, header: &str) -> Result<(), String> {
+        let colon = try!(header.find(':').ok_or(format!("Header {:?} is not of the form 'Name: value'", header)));
+        let name = header[..colon].trim();
+        if name.is_empty() {
+            return Err(format!("Header {:?} has no name", header));
+        }
+        let value = try!(resolve_secrets(header[colon+1..].trim()));
+        self.push_header(name, value)
+    }
+
+    /// Add a header, making sure it's valid, and that there is only one `Authorization`
+    fn push_header(&mut self, name: &str, value: String) -> Result<(), String> {
+        if name.contains(|c| c == '\r' || c == '\n') || value.contains(|c| c == '\r' || c == '\n') {
+            return Err(format!("Header {:?} can't have a line break in it", name));
+        }
+        if name.eq_ignore_ascii_case("Authorization") && self.headers.iter().any(|&(ref n, _)| n.eq_ignore_ascii_case("Authorization")) {
+            return Err("Only one of basic auth, a bearer token, or an Authorization header can be used".to_string());
+        }
+        self.headers.push((name.to_string(), value));
+        Ok(())
+    }
+
+    /// Use HTTP basic auth, with `username:password`
+    pub fn set_basic_auth(&mut self, userpass: &str) -> Result<(), String> {
+        let userpass = try!(resolve_secrets(userpass));
+        if ! userpass.contains(':') {
+            return Err("Basic auth must be of the form 'username:password'".to_string());
+        }
+        self.push_header("Authorization", format!("Basic {}", userpass.as_bytes().to_base64(STANDARD)))
+    }
+
+    /// Send this bearer token in the Authorization header
+    pub fn set_bearer_token(&mut self, token: &str) -> Result<(), String> {
+        let token = try!(resolve_secrets(token));
+        self.push_header("Authorization", format!("Bearer {}", token))
+    }
+
+    /// Add the headers from the `--upstream-header`, `--upstream-basic-auth` &
+    /// `--upstream-bearer-token` command line options. Exits if they are invalid.
+    pub fn add_headers_from_options(&mut self, options: &ArgMatches) {
+        let mut result = Ok(());
+        if let Some(headers) = options.values_of("upstream_header") {
+            for header in headers {
+                result = result.and_then(|_| self.add_header(header));
+            }
+        }
+        if let Some(userpass) = options.value_of("upstream_basic_auth") {
+            result = result.and_then(|_| self.set_basic_auth(userpass));
+        }
+        if let Some(token) = options.value_of("upstream_bearer_token") {
+            result = result.and_then(|_| self.set_bearer_token(token));
+        }
+        if let Err(e) = result {
+            println!("Invalid upstream header: {}", e);
+            ::std::process::exit(1);
+        }
+    }
+
+    /// HTTP headers to send with this request to this upstream. They are only sent to the hosts
+    /// of the URLs the upstream was given with, and not e.g. other hosts in its TileJSON's
+    /// `tiles`, so that secrets don't go to other servers.
+    pub fn headers_for(&self, url: &str) -> &[(String, String)] {
+        let host = url_host(url).to_lowercase();
+        if self.mirrors.iter().any(|m| m.hosts.contains(&host)) { &self.headers } else { &[] }
+    }
+
+    /// Use the `tiles`, `minzoom`, `maxzoom` & `bounds` from this upstream's TileJSON.
+    ///
+    /// The `tiles` URLs are only used if this upstream was given as a base URL. An explicit URL
+    /// template always wins.
+    pub fn update_from_tilejson(&self, tilejson: &Json) {
+        self.update_mirror_from_tilejson(0, tilejson);
+    }
+
+    /// Like `update_from_tilejson`, but with the TileJSON from this mirror. The `tiles` are only
+    /// used for this mirror. The zooms & bounds are only taken from the first mirror whose TileJSON
+    /// is used (they should all be the same), so they don't depend on which mirror is checked last.
+    /// This can be called while serving, e.g. when a mirror which was down starts working.
+    pub fn update_mirror_from_tilejson(&self, mirror: usize, tilejson: &Json) {
+        if ! self.mirrors[mirror].is_template {
+            let tms = tilejson.find("scheme").and_then(|s| s.as_string()) == Some("tms");
+            let tiles: Vec<TileURLTemplate> = tilejson.find("tiles").and_then(|t| t.as_array())
+                .map(|t| t.iter().filter_map(|u| u.as_string()).map(TileURLTemplate::parse).map(|t| if tms { t.flip_y() } else { t }).collect())
+                .unwrap_or(Vec::new());
+            if tiles.len() > 0 {
+                if ! self.headers.is_empty() {
+                    let mut other_hosts: Vec<String> = tiles.iter().flat_map(|t| t.hosts()).filter(|h| ! self.mirrors.iter().any(|m| m.hosts.contains(h))).collect();
+                    other_hosts.sort();
+                    other_hosts.dedup();
+                    if ! other_hosts.is_empty() {
+                        println!("Warning: the upstream TileJSON has tiles on {:?}, which the upstream headers won't be sent to", other_hosts);
+                    }
+                }
+                *self.mirrors[mirror].tiles.write().unwrap() = tiles;
+            }
+        }
+
+        let mut coverage = self.coverage.write().unwrap();
+        if ! coverage.from_mirror {
+            *coverage = Coverage::from_tilejson(tilejson);
+            coverage.from_mirror = true;
+        }
+    }
+
+    /// Use only the `minzoom`, `maxzoom` & `bounds` from this TileJSON (e.g. a saved copy), until
+    /// a mirror's TileJSON is used
+    pub fn update_coverage_from_tilejson(&self, tilejson: &Json) {
+        *self.coverage.write().unwrap() = Coverage::from_tilejson(tilejson);
+    }
+
+    /// The URL to download this tile from, from the next working mirror. If that mirror has many
+    /// tile URLs, they are used in turn. This doesn't move on to the next mirror (only
+    /// `try_mirrors` does that).
+    pub fn tile_url(&self, z: u8, x: u32, y: u32) -> String {
+        let start = self.next_mirror.load(Ordering::Relaxed);
+        let mirror = (0..self.mirrors.len()).map(|i| (start + i) % self.mirrors.len()).find(|&i| self.mirrors[i].is_up()).unwrap_or(start % self.mirrors.len());
+        self.mirrors[mirror].tile_url(z, x, y)
+    }
+
+    /// The order to try the mirrors in. The working mirrors are used in turn (to spread the
+    /// load), and the ones which have recently failed are only tried after them.
+    fn mirror_order(&self) -> Vec<usize> {
+        let start = self.next_mirror.fetch_add(1, Ordering::Relaxed);
+        let rotated: Vec<usize> = (0..self.mirrors.len()).map(|i| (start + i) % self.mirrors.len()).collect();
+        let mut order: Vec<usize> = rotated.iter().cloned().filter(|&i| self.mirrors[i].is_up()).collect();
+        order.extend(rotated.iter().cloned().filter(|&i| ! self.mirrors[i].is_up()));
+        order
+    }
+
+    /// Download this tile with `download` (which is given the URL), failing over to the other
+    /// mirrors if the upstream fails. Mirrors which fail are avoided for a while. The last error
+    /// is returned if all the mirrors fail.
+    pub fn try_mirrors<T, F>(&self, z: u8, x: u32, y: u32, mut download: F) -> Result<T, IompairError> where F: FnMut(&str) -> Result<T, IompairError> {
+        let mut last_error = None;
+        for i in self.mirror_order() {
+            match download(&self.mirrors[i].tile_url(z, x, y)) {
+                Ok(result) => {
+                    self.mirrors[i].mark_up();
+                    return Ok(result);
+                },
+                Err(e) => {
+                    if ! e.is_upstream_failure() {
+                        // e.g. a 404, or we couldn't save the file. Another mirror won't help
+                        return Err(e);
+                    }
+                    self.mirrors[i].mark_down();
+                    last_error = Some(e);
+                },
+            }
+        }
+        Err(last_error.unwrap())
+    }
+
+    /// Record whether a health check of this mirror worked
+    pub fn record_mirror_health(&self, mirror: usize, is_up: bool) {
+        if is_up { self.mirrors[mirror].mark_up(); } else { self.mirrors[mirror].mark_down(); }
+    }
+
+    /// Has this mirror failed since it last worked?
+    pub fn mirror_has_failed(&self, mirror: usize) -> bool {
+        self.mirrors[mirror].consecutive_failures.load(Ordering::Relaxed) > 0
+    }
+
+    /// The URL of the TileJSON for this upstream, if known
+    pub fn tilejson_url(&self) -> Option<&str> {
+        self.mirror_tilejson_url(0)
+    }
+
+    /// The URL of the TileJSON for this mirror, if known
+    pub fn mirror_tilejson_url(&self, mirror: usize) -> Option<&str> {
+        self.mirrors[mirror].tilejson_url.as_ref().map(|s| s.as_str())
+    }
+
+    /// Minimum zoom that this upstream has, according to its TileJSON
+    pub fn minzoom(&self) -> Option<u8> { self.coverage.read().unwrap().minzoom }
+
+    /// Maximum zoom that this upstream has, according to its TileJSON
+    pub fn maxzoom(&self) -> Option<u8> { self.coverage.read().unwrap().maxzoom }
+
+    /// The (west, south, east, north) bounds of this upstream, according to its TileJSON
+    pub fn bounds(&self) -> Option<(f64, f64, f64, f64)> { self.coverage.read().unwrap().bounds }
+
+    /// Cached tiles older than this are refreshed from this upstream (in the background)
+    pub fn max_age(&self) -> Option<Duration> { self.max_age }
+
+    /// Set the max age (in seconds) of cached tiles from this upstream
+    pub fn set_max_age(&mut self, max_age: &str) -> Result<(), String> {
+        let secs: u64 = try!(max_age.parse().map_err(|_| format!("Max age {:?} is not a number of seconds", max_age)));
+        self.max_age = Some(Duration::new(secs, 0));
+        Ok(())
+    }
+
+    /// Does this upstream have this tile? i.e. is it within the zoom range & bounds of the
+    /// TileJSON. If we don't know, then presume it does.
+    pub fn covers(&self, z: u8, x: u32, y: u32) -> bool {
+        let coverage = *self.coverage.read().unwrap();
+        if coverage.minzoom.map_or(false, |minzoom| z < minzoom) || coverage.maxzoom.map_or(false, |maxzoom| z > maxzoom) {
+            return false;
+        }
+        match coverage.bounds {
+            None => true,
+            Some((west, south, east, north)) => {
+                let (tile_west, tile_south, tile_east, tile_north) = tile_bounds(z, x, y);
+                // west > east means the bounds cross the antimeridian
+                let covers_lon = if west <= east { tile_west < east && tile_east > west } else { tile_west < east || tile_east > west };
+                covers_lon && tile_south < north && tile_north > south
+            }
+        }
+    }
+}
+
+mod test {
+    #[test]
+    fn test_tile_url_template() {
+        use super::TileURLTemplate;
+
+        let t = TileURLTemplate::parse("http://example.com/tiles");
+        assert_eq!(t.tile_url(2, 1, 3), "http://example.com/tiles/2/1/3.pbf");
+
+        let t = TileURLTemplate::parse("http://example.com/{z}/{x}/{y}.mvt?key=abc");
+        assert_eq!(t.tile_url(2, 1, 3), "http://example.com/2/1/3.mvt?key=abc");
+
+        let t = TileURLTemplate::parse("http://example.com/{z}/{x}/{-y}.pbf");
+        assert_eq!(t.tile_url(2, 1, 3), "http://example.com/2/1/0.pbf");
+        assert_eq!(t.tile_url(0, 0, 0), "http://example.com/0/0/0.pbf");
+
+        let t = TileURLTemplate::parse("http://example.com/{quadkey}.pbf");
+        assert_eq!(t.tile_url(3, 3, 5), "http://example.com/213.pbf");
+        assert_eq!(t.tile_url(0, 0, 0), "http://example.com/.pbf");
+
+        let t = TileURLTemplate::parse("http://{s}.example.com/{z}/{x}/{y}.pbf");
+        assert_eq!(t.tile_url(0, 0, 0), "http://a.example.com/0/0/0.pbf");
+        assert_eq!(t.tile_url(0, 0, 0), "http://b.example.com/0/0/0.pbf");
+        assert_eq!(t.tile_url(0, 0, 0), "http://c.example.com/0/0/0.pbf");
+        assert_eq!(t.tile_url(0, 0, 0), "http://a.example.com/0/0/0.pbf");
+
+        let t = TileURLTemplate::parse("http://tile{s:1,2}.example.com/{z}/{x}/{y}.pbf");
+        assert_eq!(t.tile_url(1, 1, 1), "http://tile1.example.com/1/1/1.pbf");
+        assert_eq!(t.tile_url(1, 1, 1), "http://tile2.example.com/1/1/1.pbf");
+        assert_eq!(t.tile_url(1, 1, 1), "http://tile1.example.com/1/1/1.pbf");
+    }
+
+    #[test]
+    fn test_upstream_tilejson_url() {
+        use super::Upstream;
+
+        assert_eq!(Upstream::new("http://example.com/tiles", None).tilejson_url(), Some("http://example.com/tiles/index.json"));
+        assert_eq!(Upstream::new("http://example.com/{z}/{x}/{y}.pbf", None).tilejson_url(), None);
+        assert_eq!(Upstream::new("http://example.com/{z}/{x}/{y}.pbf", Some("http://example.com/tiles.json")).tilejson_url(), Some("http://example.com/tiles.json"));
+    }
+
+    #[test]
+    fn test_upstream_from_tilejson() {
+        use super::Upstream;
+        use rustc_serialize::json::Json;
+        use std::sync::Arc;
+
+        let tilejson = Json::from_str(r#"{"tiles": ["http://a.example.com/{z}/{x}/{y}.pbf", "http://b.example.com/{z}/{x}/{y}.pbf"], "minzoom": 2, "maxzoom": 10, "bounds": [5.0, 45.0, 15.0, 55.0]}"#).unwrap();
+
+        let upstream = Upstream::new("http://example.com/tiles", None);
+        upstream.update_from_tilejson(&tilejson);
+        assert_eq!(upstream.tile_url(2, 2, 1), "http://a.example.com/2/2/1.pbf");
+        assert_eq!(upstream.tile_url(2, 2, 1), "http://b.example.com/2/2/1.pbf");
+        assert_eq!(upstream.tile_url(2, 2, 1), "http://a.example.com/2/2/1.pbf");
+        assert_eq!(upstream.minzoom(), Some(2));
+        assert_eq!(upstream.maxzoom(), Some(10));
+
+        assert!(upstream.covers(2, 2, 1));
+        assert!(! upstream.covers(1, 1, 0));
+        assert!(! upstream.covers(11, 1070, 700));
+        assert!(! upstream.covers(2, 0, 0));
+        assert!(! upstream.covers(2, 2, 2));
+
+        // The zooms & bounds come from the first mirror's TileJSON
+        let mut upstream = Upstream::new("http://a.example.com/tiles", None);
+        upstream.add_mirror("http://b.example.com/tiles", None);
+        upstream.update_mirror_from_tilejson(0, &tilejson);
+        upstream.update_mirror_from_tilejson(1, &Json::from_str(r#"{"minzoom": 0, "maxzoom": 5}"#).unwrap());
+        assert_eq!((upstream.minzoom(), upstream.maxzoom()), (Some(2), Some(10)));
+        assert!(upstream.bounds().is_some());
+
+        // A mirror which was down at startup gets its TileJSON once it works, while being shared
+        let upstream = Arc::new(Upstream::new("http://a.example.com/tiles", None));
+        assert_eq!(upstream.tile_url(2, 2, 1), "http://a.example.com/tiles/2/2/1.pbf");
+        assert_eq!(upstream.maxzoom(), None);
+        upstream.update_mirror_from_tilejson(0, &tilejson);
+        assert!(upstream.tile_url(2, 2, 1).ends_with(".example.com/2/2/1.pbf"));
+        assert_eq!(upstream.maxzoom(), Some(10));
+
+        // Explicit templates are not overridden
+        let upstream = Upstream::new("http://example.com/{z}/{x}/{y}.mvt", Some("http://example.com/tiles.json"));
+        upstream.update_from_tilejson(&tilejson);
+        assert_eq!(upstream.tile_url(2, 2, 1), "http://example.com/2/2/1.mvt");
+        assert_eq!(upstream.maxzoom(), Some(10));
+
+        // A TMS TileJSON's {y} counts from the bottom
+        let upstream = Upstream::new("http://example.com/tiles", None);
+        upstream.update_from_tilejson(&Json::from_str(r#"{"tiles": ["http://example.com/{z}/{x}/{y}.pbf"], "scheme": "tms"}"#).unwrap());
+        assert_eq!(upstream.tile_url(2, 2, 1), "http://example.com/2/2/2.pbf");
+        upstream.update_from_tilejson(&Json::from_str(r#"{"tiles": ["http://example.com/{z}/{x}/{-y}.pbf"], "scheme": "tms"}"#).unwrap());
+        assert_eq!(upstream.tile_url(2, 2, 1), "http://example.com/2/2/1.pbf");
+
+        // Bounds across the antimeridian
+        let upstream = Upstream::new("http://example.com/tiles", None);
+        upstream.update_from_tilejson(&Json::from_str(r#"{"bounds": [170.0, -10.0, -170.0, 10.0]}"#).unwrap());
+        assert!(upstream.covers(2, 0, 1));
+        assert!(upstream.covers(2, 3, 2));
+        assert!(! upstream.covers(2, 1, 1));
+        assert!(! upstream.covers(2, 2, 2));
+        assert!(! upstream.covers(2, 0, 0));
+    }
+
+    #[test]
+    fn test_upstream_mirrors() {
+        use super::Upstream;
+        use utils::IompairError;
+        use hyper::status::StatusCode;
+
+        let mut upstream = Upstream::new("http://a.example.com/{z}/{x}/{y}.pbf", None);
+        upstream.add_mirror("http://b.example.com/{z}/{x}/{y}.pbf", None);
+        assert_eq!(upstream.num_mirrors(), 2);
+
+        // Just asking for the URL doesn't move on to the next mirror
+        assert_eq!(upstream.tile_url(1, 0, 0), "http://a.example.com/1/0/0.pbf");
+        assert_eq!(upstream.tile_url(1, 0, 0), "http://a.example.com/1/0/0.pbf");
+
+        // Load is spread over the mirrors
+        assert_eq!(upstream.try_mirrors(1, 0, 0, |url| Ok(url.to_string())).unwrap(), "http://a.example.com/1/0/0.pbf");
+        assert_eq!(upstream.try_mirrors(1, 0, 0, |url| Ok(url.to_string())).unwrap(), "http://b.example.com/1/0/0.pbf");
+
+        // a is down, so fail over to b
+        let mut tried = Vec::new();
+        let result = upstream.try_mirrors(1, 0, 0, |url| {
+            tried.push(url.to_string());
+            if url.contains("//a.") { Err(IompairError::Non200ResponseError(StatusCode::ServiceUnavailable)) } else { Ok(url.to_string()) }
+        });
+        assert_eq!(result.unwrap(), "http://b.example.com/1/0/0.pbf");
+        assert_eq!(tried, vec!["http://a.example.com/1/0/0.pbf", "http://b.example.com/1/0/0.pbf"]);
+        assert!(upstream.mirror_has_failed(0));
+
+        // Now a is avoided
+        assert_eq!(upstream.tile_url(1, 0, 0), "http://b.example.com/1/0/0.pbf");
+        assert_eq!(upstream.tile_url(1, 0, 0), "http://b.example.com/1/0/0.pbf");
+
+        // A 404 isn't the mirror's fault, so there is no failover
+        let mut num_tries = 0;
+        let result: Result<(), _> = upstream.try_mirrors(1, 0, 0, |_| { num_tries += 1; Err(IompairError::Non200ResponseError(StatusCode::NotFound)) });
+        assert!(result.is_err());
+        assert_eq!(num_tries, 1);
+
+        upstream.record_mirror_health(0, true);
+        assert!(! upstream.mirror_has_failed(0));
+    }
+
+    #[test]
+    fn test_tile_bounds() {
+        use super::tile_bounds;
+
+        let (west, south, east, north) = tile_bounds(0, 0, 0);
+        assert_eq!(west, -180.);
+        assert_eq!(east, 180.);
+        assert!((north - 85.0511).abs() < 0.001);
+        assert!((south + 85.0511).abs() < 0.001);
+
+        let (west, south, east, north) = tile_bounds(1, 1, 0);
+        assert_eq!((west, east), (0., 180.));
+        assert!(south.abs() < 0.0001);
+        assert!((north - 85.0511).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_upstream_headers() {
+        use super::{Upstream, resolve_secrets};
+        use rustc_serialize::json::Json;
+        use std::env;
+
+        env::set_var("IOMPAIR_TEST_SECRET", "s3cret");
+        assert_eq!(resolve_secrets("abc"), Ok("abc".to_string()));
+        assert_eq!(resolve_secrets("key={env:IOMPAIR_TEST_SECRET}&x=1"), Ok("key=s3cret&x=1".to_string()));
+        assert!(resolve_secrets("{env:IOMPAIR_TEST_DOES_NOT_EXIST}").is_err());
+        assert!(resolve_secrets("{file:/does/not/exist}").is_err());
+        assert!(resolve_secrets("{env:IOMPAIR_TEST_SECRET").is_err());
+
+        let mut upstream = Upstream::new("http://example.com/tiles", None);
+        upstream.add_header("X-Api-Key: {env:IOMPAIR_TEST_SECRET}").unwrap();
+        upstream.set_basic_auth("user:pass").unwrap();
+        assert!(upstream.add_header("no colon").is_err());
+        assert!(upstream.add_header(": no name").is_err());
+        assert!(upstream.add_header("X-Evil: a\r\nHost: example.org").is_err());
+        assert!(upstream.set_basic_auth("nopassword").is_err());
+        // Only one Authorization header
+        assert!(upstream.set_bearer_token("token").is_err());
+        assert!(upstream.add_header("authorization: Token abc").is_err());
+
+        assert_eq!(upstream.headers_for("http://example.com/tiles/0/0/0.pbf"), &[
+            ("X-Api-Key".to_string(), "s3cret".to_string()),
+            ("Authorization".to_string(), "Basic dXNlcjpwYXNz".to_string()),
+        ]);
+        assert_eq!(upstream.headers_for("http://EXAMPLE.com:80/tiles/index.json").len(), 2);
+
+        // Not sent to other hosts in the TileJSON's tiles
+        upstream.update_from_tilejson(&Json::from_str(r#"{"tiles": ["http://cdn.example.org/{z}/{x}/{y}.pbf"]}"#).unwrap());
+        let url = upstream.tile_url(0, 0, 0);
+        assert_eq!(url, "http://cdn.example.org/0/0/0.pbf");
+        assert_eq!(upstream.headers_for(&url), &[]);
+        assert_eq!(upstream.headers_for("http://example.com.evil.org/0/0/0.pbf"), &[]);
+
+        // Sent to all the subdomains of a template, and the TileJSON host
+        let mut upstream = Upstream::new("http://{s:t1,t2}.example.com/{z}/{x}/{y}.pbf", Some("https://api.example.com/tiles.json"));
+        upstream.add_header("X-Api-Key: abc").unwrap();
+        assert_eq!(upstream.headers_for("http://t2.example.com/0/0/0.pbf").len(), 1);
+        assert_eq!(upstream.headers_for("https://api.example.com/tiles.json").len(), 1);
+        assert_eq!(upstream.headers_for("http://t3.example.com/0/0/0.pbf").len(), 0);
+
+        let mut upstream = Upstream::new("http://example.com/tiles", None);
+        upstream.set_bearer_token("token").unwrap();
+        assert!(upstream.set_bearer_token("line\nbreak").is_err());
+        assert_eq!(upstream.headers_for("http://example.com/tiles/0/0/0.pbf"), &[("Authorization".to_string(), "Bearer token".to_string())]);
+        assert!(! format!("{:?}", upstream).contains("s3cret"));
+    }
+}
@@ -2,20 +2,36 @@ extern crate hyper;
 extern crate regex;
 extern crate libflate;
 extern crate rustc_serialize;
+extern crate clap;
+extern crate filetime;
+extern crate slippy_map_tiles;
 
 use libflate::gzip::{Decoder,Encoder};
 
 use regex::Regex;
 
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::fs;
 use std::io::Write;
 use std::io;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use hyper::Client;
+use hyper::header::{Headers, UserAgent};
+
+use filetime::{FileTime, set_file_times};
+
+use slippy_map_tiles::Tile;
+
+use clap::ArgMatches;
+
+use ratelimit::RateLimiter;
+use proxy::ProxyConfig;
+use aliases::Aliases;
 
 #[derive(Debug)]
 pub enum IompairTileJsonError {
@@ -24,6 +40,10 @@ pub enum IompairTileJsonError {
     InvalidJsonError(rustc_serialize::json::BuilderError),
     NoJSONObjectError,
     JsonEncoderError(rustc_serialize::json::EncoderError),
+    /// The prefix had no tilesets
+    NoTilesetsError,
+    /// This field of this prefix's TileJSON isn't valid
+    InvalidFieldError(String, &'static str),
 }
 
 
@@ -79,6 +99,7 @@ pub enum IompairError {
     OpenFileError(io::Error),
     WriteToFileError(io::Error),
     CreateDirsError(io::Error),
+    TouchFileError(io::Error),
 }
 
 // TODO should we impl error::Error for IompairError ? Why?
@@ -88,16 +109,144 @@ pub enum IompairError {
 //}
 
 
+impl IompairError {
+    /// Is this because the upstream didn't work (rather than a problem at our end, or the tile
+    /// not existing)? i.e. is it worth trying another mirror?
+    pub fn is_upstream_failure(&self) -> bool {
+        match *self {
+            IompairError::DownloadError(_) | IompairError::ReadResponseError(_) => true,
+            IompairError::Non200ResponseError(status) => status.is_server_error() || status == hyper::status::StatusCode::TooManyRequests,
+            _ => false,
+        }
+    }
+}
+
+/// Settings for all HTTP requests to upstreams. One of these is shared between all threads.
+#[derive(Debug)]
+pub struct HttpOptions {
+    pub limiter: RateLimiter,
+    pub proxy: ProxyConfig,
+}
+
+impl HttpOptions {
+    /// Construct from the rate limiting & `--proxy` command line options (and proxy environment
+    /// variables). Exits if they are invalid.
+    pub fn from_options(options: &ArgMatches) -> Self {
+        let proxy = ProxyConfig::new(options.value_of("proxy")).unwrap_or_else(|e| {
+            println!("Invalid proxy: {}", e);
+            ::std::process::exit(1);
+        });
+        HttpOptions{ limiter: RateLimiter::from_options(options), proxy: proxy }
+    }
+}
+
+/// The validators (ETag/Last-Modified) an upstream sent for a tile, used to make conditional
+/// requests when refreshing it. They are stored next to the tile in `$TILE.validators`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    fn path_for(tile_path: &Path) -> PathBuf {
+        let mut path = tile_path.as_os_str().to_owned();
+        path.push(".validators");
+        PathBuf::from(path)
+    }
+
+    /// Read the validators for the tile at this path. If there are none (or they can't be read),
+    /// then empty validators are returned.
+    pub fn load(tile_path: &Path) -> Self {
+        let mut contents = String::new();
+        if fs::File::open(Validators::path_for(tile_path)).and_then(|mut f| f.read_to_string(&mut contents)).is_err() {
+            return Validators::default();
+        }
+        let mut validators = Validators::default();
+        for line in contents.lines() {
+            if line.starts_with("ETag: ") {
+                validators.etag = Some(line[6..].to_string());
+            } else if line.starts_with("Last-Modified: ") {
+                validators.last_modified = Some(line[15..].to_string());
+            }
+        }
+        validators
+    }
+
+    /// Save these validators for the tile at this path. If there are no validators, any old ones
+    /// are removed.
+    pub fn save(&self, tile_path: &Path) -> Result<(), IompairError> {
+        let path = Validators::path_for(tile_path);
+        if self.is_empty() {
+            if path.exists() {
+                try!(fs::remove_file(&path).map_err(IompairError::WriteToFileError));
+            }
+            return Ok(());
+        }
+
+        let mut contents = String::new();
+        if let Some(ref etag) = self.etag { contents.push_str(&format!("ETag: {}\n", etag)); }
+        if let Some(ref last_modified) = self.last_modified { contents.push_str(&format!("Last-Modified: {}\n", last_modified)); }
+        save_to_file(&path, &contents.into_bytes())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    fn from_response_headers(headers: &Headers) -> Self {
+        let header_value = |name: &str| headers.get_raw(name).and_then(|v| v.get(0)).and_then(|v| String::from_utf8(v.clone()).ok());
+        Validators{ etag: header_value("ETag"), last_modified: header_value("Last-Modified") }
+    }
+}
+
+/// The result of a (possibly conditional) download
+#[derive(Debug)]
+pub enum Download {
+    /// The contents, and the validators the upstream sent
+    Modified(Vec<u8>, Validators),
+    /// The upstream replied 304 Not Modified
+    NotModified,
+}
+
+/// The hostname part of a URL. IPv6 addresses are returned without the `[]`
+pub fn url_host(url: &str) -> &str {
+    let rest = match url.find("://") { Some(i) => &url[i+3..], None => url };
+    let end = rest.find(|c: char| c == '/' || c == '?' || c == '#').unwrap_or(rest.len());
+    let authority = &rest[..end];
+    let authority = match authority.rfind('@') { Some(i) => &authority[i+1..], None => authority };
+    if authority.starts_with('[') {
+        return match authority.find(']') { Some(i) => &authority[1..i], None => authority };
+    }
+    match authority.rfind(':') { Some(i) => &authority[..i], None => authority }
+}
+
 /// Given a URL, it'll download the URL and return the bytes, or an error of what happened. If
-/// there's an error, it tries at most `num_tries` times.
-pub fn download_url(url: &str, num_tries: u8) -> Result<Vec<u8>, IompairError> {
+/// there's an error, it tries at most `num_tries` times. Every attempt counts against the rate
+/// limiter in `http`. `headers` are extra HTTP headers (name, value) to send.
+pub fn download_url(url: &str, num_tries: u8, http: &HttpOptions, headers: &[(String, String)]) -> Result<Vec<u8>, IompairError> {
+    download_url_with_validators(url, num_tries, http, headers).map(|(bytes, _)| bytes)
+}
+
+/// Like `download_url`, but also returns the validators (ETag/Last-Modified) that the upstream
+/// sent.
+pub fn download_url_with_validators(url: &str, num_tries: u8, http: &HttpOptions, headers: &[(String, String)]) -> Result<(Vec<u8>, Validators), IompairError> {
+    match try!(download_url_conditional(url, num_tries, http, headers, &Validators::default())) {
+        Download::Modified(bytes, validators) => Ok((bytes, validators)),
+        // Shouldn't happen, since we didn't send any validators
+        Download::NotModified => Err(IompairError::Non200ResponseError(hyper::status::StatusCode::NotModified)),
+    }
+}
+
+/// Like `download_url`, but if there are `validators`, only download the URL if it has changed.
+pub fn download_url_conditional(url: &str, num_tries: u8, http: &HttpOptions, headers: &[(String, String)], validators: &Validators) -> Result<Download, IompairError> {
     // Do first download, which ensures result is always initialised
-    let mut result = download_url_single(url);
+    let mut result = download_url_single(url, http, headers, validators);
 
     // If it's OK, don't go into the loop.
     if ! result.is_ok() {
         for _ in 1..num_tries {
-            result = download_url_single(url);
+            result = download_url_single(url, http, headers, validators);
             if result.is_ok() {
                 // Successful download! Bail out early.
                 return result;
@@ -111,21 +260,50 @@ pub fn download_url(url: &str, num_tries: u8) -> Result<Vec<u8>, IompairError> {
     result
 }
 
-fn download_url_single(url: &str) -> Result<Vec<u8>, IompairError> {
-    let mut client = Client::new();
+fn download_url_single(url: &str, http: &HttpOptions, headers: &[(String, String)], validators: &Validators) -> Result<Download, IompairError> {
+    http.limiter.wait_for_request();
+
+    let mut client = match http.proxy.proxy_for(url) {
+        Some((host, port)) => Client::with_http_proxy(host, port),
+        None => Client::new(),
+    };
     
     // set the timeout to be 1 day
     client.set_read_timeout(Some(Duration::new(1 * 24 * 60 * 60, 0)));
     
-    let mut result = try!(client.get(url).send().map_err(IompairError::DownloadError));
+    let mut request_headers = Headers::new();
+    request_headers.set(UserAgent(format!("iompair/{}", env!("CARGO_PKG_VERSION"))));
+    // These can override the User-Agent
+    for &(ref name, ref value) in headers {
+        request_headers.set_raw(name.clone(), vec![value.as_bytes().to_vec()]);
+    }
+    if let Some(ref etag) = validators.etag {
+        request_headers.set_raw("If-None-Match", vec![etag.as_bytes().to_vec()]);
+    }
+    if let Some(ref last_modified) = validators.last_modified {
+        request_headers.set_raw("If-Modified-Since", vec![last_modified.as_bytes().to_vec()]);
+    }
+
+    let mut result = try!(client.get(url).headers(request_headers).send().map_err(IompairError::DownloadError));
+    if result.status == hyper::status::StatusCode::NotModified && ! validators.is_empty() {
+        return Ok(Download::NotModified);
+    }
     if result.status != hyper::status::StatusCode::Ok {
         return Err(IompairError::Non200ResponseError(result.status));
     }
 
     let mut file_contents: Vec<u8> = Vec::new();
     try!(result.read_to_end(&mut file_contents).map_err(IompairError::ReadResponseError));
+    http.limiter.record_bytes(file_contents.len());
+
+    Ok(Download::Modified(file_contents, Validators::from_response_headers(&result.headers)))
+}
 
-    Ok(file_contents)
+/// Set the modification time of this file to now
+pub fn touch(path: &Path) -> Result<(), IompairError> {
+    let now = try!(SystemTime::now().duration_since(UNIX_EPOCH).map_err(|_| IompairError::TouchFileError(io::Error::new(io::ErrorKind::Other, "Clock is before 1970"))));
+    let now = FileTime::from_seconds_since_1970(now.as_secs(), now.subsec_nanos());
+    set_file_times(path, now, now).map_err(IompairError::TouchFileError)
 }
 
 /// Saves this bytes to this path
@@ -142,20 +320,193 @@ pub fn save_to_file(path: &Path, bytes: &Vec<u8>) -> Result<(), IompairError> {
     Ok(())
 }
 
+/// What happened when refreshing a file from upstream
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SaveOutcome {
+    /// The file didn't exist before, and has been saved
+    Created,
+    /// The file existed, and has been overwritten with different contents
+    Changed,
+    /// The downloaded contents were the same as the file, so it was left untouched
+    Unchanged,
+    /// Upstream says the file hasn't changed, so the mtime was updated
+    NotModified,
+    /// The file has been deleted, or marked as stale, by `expire`
+    Invalidated,
+}
+
+impl SaveOutcome {
+    /// Was the file on disk changed?
+    pub fn is_change(&self) -> bool {
+        *self == SaveOutcome::Created || *self == SaveOutcome::Changed || *self == SaveOutcome::Invalidated
+    }
+}
+
+/// Counts of what happened to tiles during a run (stuffer, or an expire file), shared between
+/// threads. Changed tiles can also be written to a file, for invalidating downstream caches.
+#[derive(Debug)]
+pub struct RefreshStats {
+    created: AtomicUsize,
+    changed: AtomicUsize,
+    unchanged: AtomicUsize,
+    not_modified: AtomicUsize,
+    invalidated: AtomicUsize,
+    errors: AtomicUsize,
+    changed_tiles: Option<Mutex<fs::File>>,
+    prefix: Option<String>,
+}
+
+impl RefreshStats {
+    /// New, empty stats. If `changed_tiles_path` is given, the `Z/X/Y` of every changed tile is
+    /// appended to that file (as `PREFIX/Z/X/Y` if there is a `prefix`).
+    pub fn new(changed_tiles_path: Option<&str>, prefix: Option<&str>) -> Result<Self, IompairError> {
+        let changed_tiles = match changed_tiles_path {
+            None => None,
+            Some(p) => Some(Mutex::new(try!(fs::OpenOptions::new().append(true).create(true).open(p).map_err(IompairError::OpenFileError)))),
+        };
+        Ok(RefreshStats{
+            created: AtomicUsize::new(0), changed: AtomicUsize::new(0), unchanged: AtomicUsize::new(0),
+            not_modified: AtomicUsize::new(0), invalidated: AtomicUsize::new(0), errors: AtomicUsize::new(0), changed_tiles: changed_tiles,
+            prefix: prefix.map(|p| p.to_string()),
+        })
+    }
+
+    /// Record what happened when downloading this tile
+    pub fn record(&self, z: u8, x: u32, y: u32, result: &Result<SaveOutcome, IompairError>) {
+        let counter = match *result {
+            Ok(SaveOutcome::Created) => &self.created,
+            Ok(SaveOutcome::Changed) => &self.changed,
+            Ok(SaveOutcome::Unchanged) => &self.unchanged,
+            Ok(SaveOutcome::NotModified) => &self.not_modified,
+            Ok(SaveOutcome::Invalidated) => &self.invalidated,
+            Err(_) => &self.errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        if let (&Ok(outcome), Some(ref changed_tiles)) = (result, self.changed_tiles.as_ref()) {
+            if outcome.is_change() {
+                let mut changed_tiles = changed_tiles.lock().unwrap();
+                let line = match self.prefix {
+                    None => format!("{}/{}/{}", z, x, y),
+                    Some(ref prefix) => format!("{}/{}/{}/{}", prefix, z, x, y),
+                };
+                writeln!(changed_tiles, "{}", line).unwrap_or_else(|e| {
+                    println!("Error writing to changed tiles list: {:?}", e);
+                });
+            }
+        }
+    }
+
+    /// Number of tiles which were changed on disk
+    pub fn num_changed(&self) -> usize {
+        self.created.load(Ordering::Relaxed) + self.changed.load(Ordering::Relaxed) + self.invalidated.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Display for RefreshStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} tiles changed ({} new, {} updated, {} invalidated), {} unchanged, {} not modified upstream, {} errors",
+               self.num_changed(), self.created.load(Ordering::Relaxed), self.changed.load(Ordering::Relaxed), self.invalidated.load(Ordering::Relaxed),
+               self.unchanged.load(Ordering::Relaxed), self.not_modified.load(Ordering::Relaxed), self.errors.load(Ordering::Relaxed))
+    }
+}
+
+/// `expire --mode mark-stale` marks a tile as stale with an empty `$TILE.stale` file next to it.
+/// `serve` will then try to refresh it from upstream before serving it.
+fn stale_marker_path(tile_path: &Path) -> PathBuf {
+    let mut path = tile_path.as_os_str().to_owned();
+    path.push(".stale");
+    PathBuf::from(path)
+}
+
+/// Has the tile at this path been marked as stale?
+pub fn is_stale(tile_path: &Path) -> bool {
+    stale_marker_path(tile_path).exists()
+}
+
+/// Mark the tile at this path as stale
+pub fn mark_stale(tile_path: &Path) -> Result<(), IompairError> {
+    fs::File::create(stale_marker_path(tile_path)).map(|_| ()).map_err(IompairError::WriteToFileError)
+}
+
+/// Remove the stale marker for this tile (if there is one)
+pub fn clear_stale(tile_path: &Path) -> Result<(), IompairError> {
+    let marker = stale_marker_path(tile_path);
+    if marker.exists() {
+        try!(fs::remove_file(marker).map_err(IompairError::WriteToFileError));
+    }
+    Ok(())
+}
+
+/// Delete the tile at this path, and anything we have stored alongside it
+pub fn delete_tile(tile_path: &Path) -> Result<(), IompairError> {
+    try!(fs::remove_file(tile_path).map_err(IompairError::WriteToFileError));
+    try!(Validators::default().save(tile_path));
+    clear_stale(tile_path)
+}
+
+/// Is the file at this path exactly these bytes?
+fn file_has_contents(path: &Path, bytes: &[u8]) -> bool {
+    match path.metadata() {
+        Ok(ref m) if m.len() == bytes.len() as u64 => {},
+        _ => { return false; },
+    }
+    let mut existing = Vec::with_capacity(bytes.len());
+    match fs::File::open(path).and_then(|mut f| f.read_to_end(&mut existing)) {
+        Ok(_) => existing == bytes,
+        Err(_) => false,
+    }
+}
+
 /// Downloads the URL and if it went OK, saves the contents to path. Returns Error if something
 /// went wrong.
-pub fn download_url_and_save_to_file(url: &str, path: &Path) -> Result<(), IompairError> {
-    let contents = try!(download_url(url, 10));
+///
+/// If the file already exists, and we have validators (ETag/Last-Modified) for it, then a
+/// conditional request is made. If the upstream says it hasn't changed, the file is kept, and only
+/// the mtime is updated. If the downloaded contents are the same as the existing file, it is not
+/// written to (so the mtime doesn't change). Either way, the tile is no longer stale.
+pub fn download_url_and_save_to_file(url: &str, path: &Path, num_tries: u8, http: &HttpOptions, headers: &[(String, String)]) -> Result<SaveOutcome, IompairError> {
+    let existed = path.exists();
+    let validators = if existed { Validators::load(path) } else { Validators::default() };
+
+    let outcome = match try!(download_url_conditional(url, num_tries, http, headers, &validators)) {
+        Download::NotModified => {
+            try!(touch(path));
+            SaveOutcome::NotModified
+        },
+        Download::Modified(contents, new_validators) => {
+            let outcome = if existed && file_has_contents(path, &contents) {
+                SaveOutcome::Unchanged
+            } else {
+                if let Err(e) = save_to_file(path, &contents) {
+                    // The old validators might not match what's on disk now, so the next request
+                    // must be unconditional
+                    Validators::default().save(path).ok();
+                    return Err(e);
+                }
+                if existed { SaveOutcome::Changed } else { SaveOutcome::Created }
+            };
+            // Only saved once the tile is, so they always describe what's on disk
+            if new_validators != validators {
+                try!(new_validators.save(path));
+            }
+            outcome
+        },
+    };
 
-    save_to_file(path, &contents)
+    try!(clear_stale(path));
+    Ok(outcome)
 }
 
 /// A prefix for a URL path
 /// Like /foo__bar/index.json which is the concat of both foo and bar levels.
 /// /index.json would be no other layers invovled
+/// /basemap/index.json could be an alias (from `--aliases`) for some prefixes
 #[derive(Debug, PartialEq, Eq)]
 pub struct URLPathPrefix {
-    parts: Option<Vec<String>>
+    parts: Option<Vec<String>>,
+    /// The alias used in the URL, if any
+    alias: Option<String>,
 }
 
 impl URLPathPrefix {
@@ -176,12 +527,12 @@ impl URLPathPrefix {
                 Some(new)
             }
         };
-        URLPathPrefix{ parts: new_parts }
+        URLPathPrefix{ parts: new_parts, alias: None }
     }
 
     #[allow(unused)]
     /// Shortcut to create a URLPathPrefix with no prefix
-    fn none() -> Self { URLPathPrefix{ parts: None } }
+    fn none() -> Self { URLPathPrefix{ parts: None, alias: None } }
 
     #[allow(unused)]
     /// Shortcut to create a URLPathPrefix with the following parts
@@ -189,20 +540,29 @@ impl URLPathPrefix {
         URLPathPrefix::new(Some(parts))
     }
 
-    /// Construct a URLPathPrefix from a path string, like "foo__bar" or ""
-    fn parse<S>(s: Option<S>) -> Self where S: Into<String> {
+    /// Construct a URLPathPrefix from a path string, like "foo__bar", "basemap" (an alias) or "".
+    /// `None` if it's not allowed by the `aliases`.
+    pub fn parse<S>(s: Option<S>, aliases: &Aliases) -> Option<Self> where S: Into<String> {
         match s {
-            None => URLPathPrefix{ parts: None },
+            None => Some(URLPathPrefix{ parts: None, alias: None }),
             Some(mystring) => {
-                URLPathPrefix{ parts: Some(mystring.into().split("__").map(|x| x.to_string()).filter(|x| x != "").collect::<Vec<String>>()) }
+                let mystring = mystring.into();
+                let alias = if aliases.get(&mystring).is_some() { Some(mystring.clone()) } else { None };
+                aliases.resolve(&mystring).map(|parts| URLPathPrefix{ parts: Some(parts), alias: alias })
             }
         }
     }
 
+    /// The alias this was requested with, if any
+    pub fn alias(&self) -> Option<&str> {
+        self.alias.as_ref().map(|a| a.as_str())
+    }
+
     pub fn path_with_trailing_slash(&self) -> String {
-        match self.parts {
-            None => "".to_string(),
-            Some(ref p) => format!("{}/", p.join("__")),
+        match (&self.alias, &self.parts) {
+            (&Some(ref alias), _) => format!("{}/", alias),
+            (&None, &None) => "".to_string(),
+            (&None, &Some(ref p)) => format!("{}/", p.join("__")),
         }
     }
 
@@ -235,9 +595,10 @@ impl URLPathPrefix {
 
 impl fmt::Display for URLPathPrefix {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self.parts {
-            None => write!(f, ""),
-            Some(ref parts) => write!(f, "{}", parts.join("__")),
+        match (&self.alias, &self.parts) {
+            (&Some(ref alias), _) => write!(f, "{}", alias),
+            (&None, &None) => write!(f, ""),
+            (&None, &Some(ref parts)) => write!(f, "{}", parts.join("__")),
         }
     }
 }
@@ -245,12 +606,102 @@ impl fmt::Display for URLPathPrefix {
 #[derive(Debug, PartialEq, Eq)]
 pub enum URL {
     Invalid,
-    Tilejson(URLPathPrefix),
-    Tile(URLPathPrefix, u8, u32, u32, String),
+    /// The map viewer page (`/`)
+    Viewer,
+    /// The list of all tilesets, and the JSONP callback (if any)
+    Catalog(Option<String>),
+    /// The layers to include (from `?layers=`), `None` for all of them, and the JSONP callback
+    /// (from `?callback=`), if any.
+    Tilejson(URLPathPrefix, Option<Vec<String>>, Option<String>),
+    Tile(URLPathPrefix, u8, u32, u32, String, Option<Vec<String>>),
+    /// A tile as GeoJSON, the extension is `geojson` or `json`
+    GeoJSON(URLPathPrefix, u8, u32, u32, String, Option<Vec<String>>),
+    /// A style (`/styles/NAME.json`), and the JSONP callback, if any
+    Style(String, Option<String>),
+    /// A sprite file, e.g. `basic.json` or `basic@2x.png`
+    Sprite(String),
+    /// Glyphs for a font stack (the fonts to try, in order), and the range, e.g. `0-255`
+    Glyphs(Vec<String>, String),
 }
 
+/// Decode `%XX` escapes in a URL query value. `None` if they are invalid.
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if i + 3 > bytes.len() {
+                return None;
+            }
+            let hex = match ::std::str::from_utf8(&bytes[i+1..i+3]) { Ok(h) => h, Err(_) => return None };
+            decoded.push(match u8::from_str_radix(hex, 16) { Ok(b) => b, Err(_) => return None });
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
 
-pub fn parse_url(url: &str, maxzoom: u8) -> URL {
+/// Parse a font stack from a glyphs URL, e.g. `Open%20Sans%20Regular,Arial%20Unicode%20MS%20Regular`.
+/// `None` if any font name could be used to escape the fonts directory.
+fn parse_fontstack(fontstack: &str) -> Option<Vec<String>> {
+    let fontstack = match percent_decode(fontstack) { Some(f) => f, None => return None };
+    let fonts: Vec<String> = fontstack.split(',').map(|f| f.trim().to_string()).collect();
+    if fonts.iter().any(|f| f.is_empty() || f.starts_with('.') || f.contains('/') || f.contains('\\')) {
+        None
+    } else {
+        Some(fonts)
+    }
+}
+
+/// Is this a glyph range MapLibre would ask for, like `0-255` or `256-511`?
+fn is_valid_glyph_range(start: u32, end: u32) -> bool {
+    start % 256 == 0 && end == start + 255 && end <= 65535
+}
+
+/// Is this a safe JSONP callback name, like `jQuery123_456` or `window.loaded`?
+fn is_valid_callback(callback: &str) -> bool {
+    ! callback.is_empty() && callback.len() <= 128 && callback.chars().all(|c| match c { 'a' ... 'z' | 'A' ... 'Z' | '0' ... '9' | '_' | '$' | '.' => true, _ => false })
+}
+
+/// Parse the query string of a URL, returning the `?layers=` and the JSONP `?callback=` (if
+/// given). `timeout` (used by some TileJSON clients) must be a number, other parameters are
+/// ignored. `Err` if it's invalid.
+fn parse_query(query: Option<&str>) -> Result<(Option<Vec<String>>, Option<String>), ()> {
+    let mut layers = None;
+    let mut callback = None;
+    for param in query.unwrap_or("").split('&').filter(|p| ! p.is_empty()) {
+        let (key, value) = match param.find('=') {
+            Some(i) => (&param[..i], &param[i+1..]),
+            None => (param, ""),
+        };
+        match key {
+            "timeout" => { try!(value.parse::<u64>().map_err(|_| ())); },
+            "layers" => {
+                let value = try!(percent_decode(value).ok_or(()));
+                let names: Vec<String> = value.split(',').map(|l| l.trim().to_string()).filter(|l| ! l.is_empty()).collect();
+                if names.is_empty() {
+                    return Err(());
+                }
+                layers = Some(names);
+            },
+            "callback" => {
+                let value = try!(percent_decode(value).ok_or(()));
+                if ! is_valid_callback(&value) {
+                    return Err(());
+                }
+                callback = Some(value);
+            },
+            _ => {},
+        }
+    }
+    Ok((layers, callback))
+}
+
+pub fn parse_url(url: &str, maxzoom: u8, aliases: &Aliases) -> URL {
 
     // Macro which returns URL::Invalid if the Option<T> is None. Makes it easier for early return
     macro_rules! or_invalid {
@@ -260,20 +711,49 @@ pub fn parse_url(url: &str, maxzoom: u8) -> URL {
 
     // TODO Use a proper URL parsing library, not just regexes
 
-    if let Some(caps) = Regex::new("^(/(?P<prefix>[a-zA-Z0-9_-]+))?/index.json(\\?timeout=[0-9]+)?$").unwrap().captures(url) {
-        URL::Tilejson(URLPathPrefix::parse(caps.name("prefix")))
+    let (url, query) = match url.find('?') {
+        Some(i) => (&url[..i], Some(&url[i+1..])),
+        None => (url, None),
+    };
+    let (layers, callback) = or_invalid!(parse_query(query).ok());
+
+    if url == "/" {
+        return URL::Viewer;
+    }
+    if url == "/catalog.json" {
+        return URL::Catalog(callback);
+    }
+
+    if let Some(caps) = Regex::new("^(/(?P<prefix>[a-zA-Z0-9_-]+))?/index.json$").unwrap().captures(url) {
+        URL::Tilejson(or_invalid!(URLPathPrefix::parse(caps.name("prefix"), aliases)), layers, callback)
     } else {
-        let re = Regex::new("^(/(?P<prefix>[a-zA-Z0-9_-]+))?/(?P<z>[0-9]?[0-9])/(?P<x>[0-9]+)/(?P<y>[0-9]+)\\.(?P<ext>.{3,4})$").unwrap();
+        let re = Regex::new("^(/(?P<prefix>[a-zA-Z0-9_-]+))?/(?P<z>[0-9]?[0-9])/(?P<x>[0-9]+)/(?P<y>[0-9]+)\\.(?P<ext>.{3,4}|geojson)$").unwrap();
         if let Some(caps) = re.captures(url) {
             let z: u8 = or_invalid!(or_invalid!(caps.name("z")).parse().ok());
             if z > maxzoom {
                 URL::Invalid
             } else {
+                let pathprefix = or_invalid!(URLPathPrefix::parse(caps.name("prefix"), aliases));
                 let x: u32 = or_invalid!(or_invalid!(caps.name("x")).parse().ok());
                 let y: u32 = or_invalid!(or_invalid!(caps.name("y")).parse().ok());
                 let ext: String = or_invalid!(caps.name("ext")).to_owned();
-                URL::Tile(URLPathPrefix::parse(caps.name("prefix")), z, x, y, ext)
+                if ext == "geojson" || ext == "json" {
+                    URL::GeoJSON(pathprefix, z, x, y, ext, layers)
+                } else {
+                    URL::Tile(pathprefix, z, x, y, ext, layers)
+                }
+            }
+        } else if let Some(caps) = Regex::new("^/styles/(?P<name>[a-zA-Z0-9_-]+)\\.json$").unwrap().captures(url) {
+            URL::Style(or_invalid!(caps.name("name")).to_owned(), callback)
+        } else if let Some(caps) = Regex::new("^/sprites/(?P<filename>[a-zA-Z0-9_-]+(@[23]x)?\\.(json|png))$").unwrap().captures(url) {
+            URL::Sprite(or_invalid!(caps.name("filename")).to_owned())
+        } else if let Some(caps) = Regex::new("^/fonts/(?P<fontstack>[^/]+)/(?P<start>[0-9]+)-(?P<end>[0-9]+)\\.pbf$").unwrap().captures(url) {
+            let start: u32 = or_invalid!(or_invalid!(caps.name("start")).parse().ok());
+            let end: u32 = or_invalid!(or_invalid!(caps.name("end")).parse().ok());
+            if ! is_valid_glyph_range(start, end) {
+                return URL::Invalid;
             }
+            URL::Glyphs(or_invalid!(parse_fontstack(or_invalid!(caps.name("fontstack")))), format!("{}-{}", start, end))
         } else {
             URL::Invalid
         }
@@ -293,8 +773,20 @@ fn gunzip(compressed_data: &[u8]) -> Vec<u8> {
     result
 }
 
+/// Uncompress these vector tile bytes if they are gzipped (otherwise they are returned as is).
+/// Also returns whether they were gzipped.
+pub fn maybe_gunzip(data: &[u8]) -> Result<(Vec<u8>, bool), String> {
+    if data.len() < 2 || data[0] != 0x1f || data[1] != 0x8b {
+        return Ok((data.to_vec(), false));
+    }
+    let mut d = try!(Decoder::new(data).map_err(|e| format!("Invalid gzip data: {}", e)));
+    let mut result = Vec::new();
+    try!(d.read_to_end(&mut result).map_err(|e| format!("Invalid gzip data: {}", e)));
+    Ok((result, true))
+}
+
 /// Given some bytes, compress them with gzip
-fn gzip(uncompressed_data: &[u8]) -> Vec<u8> {
+pub fn gzip(uncompressed_data: &[u8]) -> Vec<u8> {
     let mut e = Encoder::new(Vec::new()).unwrap();
     e.write_all(uncompressed_data).unwrap();
     e.finish().into_result().unwrap()
@@ -344,52 +836,211 @@ pub enum DirectoryLayout {
     ZXYPath,
 }
 
+impl DirectoryLayout {
+    /// The path of this tile, relative to the root of the tile cache
+    pub fn tile_path(&self, tile: &Tile, ext: &str) -> String {
+        match *self {
+            DirectoryLayout::TCPath => tile.tc_path(ext),
+            DirectoryLayout::TSPath => tile.ts_path(ext),
+            DirectoryLayout::ZXYPath => tile.zxy_path(ext),
+        }
+    }
+}
+
 mod test {
+    /// Start a stand-in HTTP server which accepts one connection, replies with `response`, and
+    /// returns the request it got
+    #[allow(unused)]
+    fn serve_once(response: &'static [u8]) -> (u16, ::std::thread::JoinHandle<String>) {
+        use std::net::TcpListener;
+        use std::io::{Read, Write};
+        use std::thread;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while ! String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                if n == 0 { break; }
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(response).unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn test_download_url_via_proxy() {
+        use super::{download_url, HttpOptions};
+        use ratelimit::RateLimiter;
+        use proxy::ProxyConfig;
+
+        // A stand-in proxy, which records the request, and always returns the same response
+        let (port, proxy_thread) = serve_once(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello");
+
+        let proxy = ProxyConfig::new(Some(&format!("http://127.0.0.1:{}/", port))).unwrap();
+        let http = HttpOptions{ limiter: RateLimiter::unlimited(), proxy: proxy };
+        let headers = vec![("X-Test".to_string(), "yes".to_string())];
+        let result = download_url("http://tiles.example.invalid/0/0/0.pbf", 1, &http, &headers);
+
+        let request = proxy_thread.join().unwrap();
+        assert_eq!(result.unwrap(), b"hello".to_vec());
+        assert!(request.starts_with("GET http://tiles.example.invalid/0/0/0.pbf HTTP/1.1\r\n"));
+        assert!(request.contains("X-Test: yes\r\n"));
+        assert!(request.contains(&format!("User-Agent: iompair/{}\r\n", env!("CARGO_PKG_VERSION"))));
+    }
+
+    #[test]
+    fn test_conditional_download() {
+        use super::{download_url_and_save_to_file, HttpOptions, Validators, SaveOutcome};
+        use ratelimit::RateLimiter;
+        use proxy::ProxyConfig;
+        use std::fs;
+        use std::env;
+
+        let dir = env::temp_dir().join(format!("iompair-test-conditional-{}", ::std::process::id()));
+        let tile_path = dir.join("0/0/0.pbf");
+        let http = HttpOptions{ limiter: RateLimiter::unlimited(), proxy: ProxyConfig::default() };
+
+        // First download saves the validators
+        let (port, server) = serve_once(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nETag: \"abc\"\r\nConnection: close\r\n\r\nhello");
+        let result = download_url_and_save_to_file(&format!("http://127.0.0.1:{}/0/0/0.pbf", port), &tile_path, 10, &http, &[]);
+        assert!(! server.join().unwrap().contains("If-None-Match"));
+        assert_eq!(result.unwrap(), SaveOutcome::Created);
+        assert_eq!(Validators::load(&tile_path), Validators{ etag: Some("\"abc\"".to_string()), last_modified: None });
+
+        // Second one sends them, and the file is kept on 304
+        let (port, server) = serve_once(b"HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n");
+        let result = download_url_and_save_to_file(&format!("http://127.0.0.1:{}/0/0/0.pbf", port), &tile_path, 10, &http, &[]);
+        assert!(server.join().unwrap().contains("If-None-Match: \"abc\"\r\n"));
+        assert_eq!(result.unwrap(), SaveOutcome::NotModified);
+        assert_eq!(fs::read(&tile_path).unwrap(), b"hello".to_vec());
+
+        // Same contents, so not rewritten
+        let (port, server) = serve_once(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nETag: \"def\"\r\nConnection: close\r\n\r\nhello");
+        let result = download_url_and_save_to_file(&format!("http://127.0.0.1:{}/0/0/0.pbf", port), &tile_path, 10, &http, &[]);
+        server.join().unwrap();
+        assert_eq!(result.unwrap(), SaveOutcome::Unchanged);
+        assert_eq!(Validators::load(&tile_path).etag, Some("\"def\"".to_string()));
+
+        // New contents
+        let (port, server) = serve_once(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nworld");
+        let result = download_url_and_save_to_file(&format!("http://127.0.0.1:{}/0/0/0.pbf", port), &tile_path, 10, &http, &[]);
+        server.join().unwrap();
+        assert_eq!(result.unwrap(), SaveOutcome::Changed);
+        assert_eq!(fs::read(&tile_path).unwrap(), b"world".to_vec());
+        assert_eq!(Validators::load(&tile_path), Validators::default());
+
+        // Refreshing a stale tile means it's not stale anymore
+        super::mark_stale(&tile_path).unwrap();
+        assert!(super::is_stale(&tile_path));
+        let (port, server) = serve_once(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nworld");
+        let result = download_url_and_save_to_file(&format!("http://127.0.0.1:{}/0/0/0.pbf", port), &tile_path, 10, &http, &[]);
+        server.join().unwrap();
+        assert_eq!(result.unwrap(), SaveOutcome::Unchanged);
+        assert!(! super::is_stale(&tile_path));
+
+        // Deleting removes everything
+        super::mark_stale(&tile_path).unwrap();
+        super::delete_tile(&tile_path).unwrap();
+        assert!(! tile_path.exists());
+        assert!(! super::is_stale(&tile_path));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn test_urlprefix() {
         use super::URLPathPrefix;
+        use aliases::Aliases;
 
         // Some variables to prevent "cannot infer type"
         let none_string: Option<String> = None;
         let none_vec: Option<Vec<String>> = None;
         let empty_vec: Vec<String> = vec![];
-
-        assert_eq!(URLPathPrefix::parse(none_string.clone()), URLPathPrefix::new(none_vec));
-        assert_eq!(URLPathPrefix::parse(Some("")), URLPathPrefix::from_parts(empty_vec));
-        assert_eq!(URLPathPrefix::parse(Some("abc")), URLPathPrefix::from_parts(vec!["abc".to_string()]));
-        assert_eq!(URLPathPrefix::parse(Some("abc_xyz")), URLPathPrefix::from_parts(vec!["abc_xyz".to_string()]));
-        assert_eq!(URLPathPrefix::parse(Some("abc__xyz")), URLPathPrefix::from_parts(vec!["abc".to_string(), "xyz".to_string()]));
-
-        assert_eq!(URLPathPrefix::parse(none_string.clone()).paths("/tmp"), vec!["/tmp"]);
-        assert_eq!(URLPathPrefix::parse(Some("abc")).paths("/tmp"), vec!["/tmp/abc"]);
-        assert_eq!(URLPathPrefix::parse(Some("abc__xyz")).paths("/tmp"), vec!["/tmp/abc", "/tmp/xyz"]);
-        assert_eq!(URLPathPrefix::parse(Some("abc__xyz__foo__bar")).paths("/tmp"), vec!["/tmp/abc", "/tmp/xyz", "/tmp/foo", "/tmp/bar"]);
+        let aliases = Aliases::none();
+
+        assert_eq!(URLPathPrefix::parse(none_string.clone(), &aliases), Some(URLPathPrefix::new(none_vec)));
+        assert_eq!(URLPathPrefix::parse(Some(""), &aliases), Some(URLPathPrefix::from_parts(empty_vec)));
+        assert_eq!(URLPathPrefix::parse(Some("abc"), &aliases), Some(URLPathPrefix::from_parts(vec!["abc".to_string()])));
+        assert_eq!(URLPathPrefix::parse(Some("abc_xyz"), &aliases), Some(URLPathPrefix::from_parts(vec!["abc_xyz".to_string()])));
+        assert_eq!(URLPathPrefix::parse(Some("abc__xyz"), &aliases), Some(URLPathPrefix::from_parts(vec!["abc".to_string(), "xyz".to_string()])));
+
+        assert_eq!(URLPathPrefix::parse(none_string.clone(), &aliases).unwrap().paths("/tmp"), vec!["/tmp"]);
+        assert_eq!(URLPathPrefix::parse(Some("abc"), &aliases).unwrap().paths("/tmp"), vec!["/tmp/abc"]);
+        assert_eq!(URLPathPrefix::parse(Some("abc__xyz"), &aliases).unwrap().paths("/tmp"), vec!["/tmp/abc", "/tmp/xyz"]);
+        assert_eq!(URLPathPrefix::parse(Some("abc__xyz__foo__bar"), &aliases).unwrap().paths("/tmp"), vec!["/tmp/abc", "/tmp/xyz", "/tmp/foo", "/tmp/bar"]);
     }
 
     #[test]
     fn test_url_parse() {
         use super::{parse_url, URL, URLPathPrefix};
-
-
-        assert_eq!(parse_url("/", 22), URL::Invalid);
-        assert_eq!(parse_url("/robots.txt", 22), URL::Invalid);
-        assert_eq!(parse_url("/index.json", 22), URL::Tilejson(URLPathPrefix::none()));
-        assert_eq!(parse_url("/2/12/12.png", 22), URL::Tile(URLPathPrefix::none(), 2, 12, 12, "png".to_owned()));
-        assert_eq!(parse_url("/2/12/12.png", 1), URL::Invalid);
-
-        assert_eq!(parse_url("/foobar/index.json", 22), URL::Tilejson(URLPathPrefix::from_parts(vec!["foobar"])));
-        assert_eq!(parse_url("/foobar/2/12/12.png", 22), URL::Tile(URLPathPrefix::from_parts(vec!["foobar"]), 2, 12, 12, "png".to_owned()));
-        assert_eq!(parse_url("/HELLO_there-number-3/2/12/12.png", 22), URL::Tile(URLPathPrefix::from_parts(vec!["HELLO_there-number-3"]), 2, 12, 12, "png".to_owned()));
-        assert_eq!(parse_url("/no spaces/2/12/12.png", 22), URL::Invalid);
-        assert_eq!(parse_url("bad bad bad no spaces/2/12/12.png", 22), URL::Invalid);
-
-        assert_eq!(parse_url("/foo__bar/index.json", 22), URL::Tilejson(URLPathPrefix::from_parts(vec!["foo", "bar"])));
-        assert_eq!(parse_url("/foo__bar/0/0/0.png", 22), URL::Tile(URLPathPrefix::from_parts(vec!["foo", "bar"]), 0, 0, 0, "png".to_string()));
-        assert_eq!(parse_url("/bar__foo/0/0/0.png", 22), URL::Tile(URLPathPrefix::from_parts(vec!["bar", "foo"]), 0, 0, 0, "png".to_string()));
-        assert_eq!(parse_url("/foo__bar__baz/0/0/0.png", 22), URL::Tile(URLPathPrefix::from_parts(vec!["foo", "bar", "baz"]), 0, 0, 0, "png".to_string()));
-
-        assert_eq!(parse_url("/index.json?timeout=10", 22), URL::Tilejson(URLPathPrefix::none()));
-        assert_eq!(parse_url("/index.json?timeout=aaa", 22), URL::Invalid);
+        use aliases::Aliases;
+
+        let none = Aliases::none();
+        assert_eq!(parse_url("/", 22, &none), URL::Viewer);
+        assert_eq!(parse_url("/?x=1", 22, &none), URL::Viewer);
+        assert_eq!(parse_url("/robots.txt", 22, &none), URL::Invalid);
+        assert_eq!(parse_url("/index.json", 22, &none), URL::Tilejson(URLPathPrefix::none(), None, None));
+        assert_eq!(parse_url("/2/12/12.png", 22, &none), URL::Tile(URLPathPrefix::none(), 2, 12, 12, "png".to_owned(), None));
+        assert_eq!(parse_url("/2/12/12.png", 1, &none), URL::Invalid);
+
+        assert_eq!(parse_url("/foobar/index.json", 22, &none), URL::Tilejson(URLPathPrefix::from_parts(vec!["foobar"]), None, None));
+        assert_eq!(parse_url("/foobar/2/12/12.png", 22, &none), URL::Tile(URLPathPrefix::from_parts(vec!["foobar"]), 2, 12, 12, "png".to_owned(), None));
+        assert_eq!(parse_url("/HELLO_there-number-3/2/12/12.png", 22, &none), URL::Tile(URLPathPrefix::from_parts(vec!["HELLO_there-number-3"]), 2, 12, 12, "png".to_owned(), None));
+        assert_eq!(parse_url("/no spaces/2/12/12.png", 22, &none), URL::Invalid);
+        assert_eq!(parse_url("bad bad bad no spaces/2/12/12.png", 22, &none), URL::Invalid);
+
+        assert_eq!(parse_url("/foo__bar/index.json", 22, &none), URL::Tilejson(URLPathPrefix::from_parts(vec!["foo", "bar"]), None, None));
+        assert_eq!(parse_url("/foo__bar/0/0/0.png", 22, &none), URL::Tile(URLPathPrefix::from_parts(vec!["foo", "bar"]), 0, 0, 0, "png".to_string(), None));
+        assert_eq!(parse_url("/bar__foo/0/0/0.png", 22, &none), URL::Tile(URLPathPrefix::from_parts(vec!["bar", "foo"]), 0, 0, 0, "png".to_string(), None));
+        assert_eq!(parse_url("/foo__bar__baz/0/0/0.png", 22, &none), URL::Tile(URLPathPrefix::from_parts(vec!["foo", "bar", "baz"]), 0, 0, 0, "png".to_string(), None));
+
+        assert_eq!(parse_url("/index.json?timeout=10", 22, &none), URL::Tilejson(URLPathPrefix::none(), None, None));
+        assert_eq!(parse_url("/index.json?timeout=aaa", 22, &none), URL::Invalid);
+
+        assert_eq!(parse_url("/foo/2/1/3.geojson", 22, &none), URL::GeoJSON(URLPathPrefix::from_parts(vec!["foo"]), 2, 1, 3, "geojson".to_string(), None));
+        assert_eq!(parse_url("/2/1/3.json", 22, &none), URL::GeoJSON(URLPathPrefix::none(), 2, 1, 3, "json".to_string(), None));
+        assert_eq!(parse_url("/2/1/3.geojson", 1, &none), URL::Invalid);
+
+        assert_eq!(parse_url("/foo/2/1/3.pbf?layers=water,roads", 22, &none), URL::Tile(URLPathPrefix::from_parts(vec!["foo"]), 2, 1, 3, "pbf".to_string(), Some(vec!["water".to_string(), "roads".to_string()])));
+        assert_eq!(parse_url("/2/1/3.geojson?layers=water%2Croads&x=1", 22, &none), URL::GeoJSON(URLPathPrefix::none(), 2, 1, 3, "geojson".to_string(), Some(vec!["water".to_string(), "roads".to_string()])));
+        assert_eq!(parse_url("/foo/index.json?timeout=10&layers=water", 22, &none), URL::Tilejson(URLPathPrefix::from_parts(vec!["foo"]), Some(vec!["water".to_string()]), None));
+        assert_eq!(parse_url("/2/1/3.pbf?layers=", 22, &none), URL::Invalid);
+        assert_eq!(parse_url("/2/1/3.pbf?layers=%zz", 22, &none), URL::Invalid);
+
+        assert_eq!(parse_url("/catalog.json", 22, &none), URL::Catalog(None));
+        assert_eq!(parse_url("/catalog.json?callback=loaded", 22, &none), URL::Catalog(Some("loaded".to_string())));
+        assert_eq!(parse_url("/foo/index.json?callback=jQuery_1.cb$", 22, &none), URL::Tilejson(URLPathPrefix::from_parts(vec!["foo"]), None, Some("jQuery_1.cb$".to_string())));
+        assert_eq!(parse_url("/foo/index.json?callback=alert(1)", 22, &none), URL::Invalid);
+        assert_eq!(parse_url("/foo/index.json?callback=", 22, &none), URL::Invalid);
+        assert_eq!(parse_url("/foo/catalog.json", 22, &none), URL::Invalid);
+
+        assert_eq!(parse_url("/styles/basic.json", 22, &none), URL::Style("basic".to_string(), None));
+        assert_eq!(parse_url("/styles/basic.json?callback=cb", 22, &none), URL::Style("basic".to_string(), Some("cb".to_string())));
+        assert_eq!(parse_url("/styles/index.json", 22, &none), URL::Tilejson(URLPathPrefix::from_parts(vec!["styles"]), None, None));
+        assert_eq!(parse_url("/styles/../secret.json", 22, &none), URL::Invalid);
+        assert_eq!(parse_url("/sprites/basic.json", 22, &none), URL::Sprite("basic.json".to_string()));
+        assert_eq!(parse_url("/sprites/basic@2x.png", 22, &none), URL::Sprite("basic@2x.png".to_string()));
+        assert_eq!(parse_url("/sprites/basic.txt", 22, &none), URL::Invalid);
+        assert_eq!(parse_url("/fonts/Open%20Sans%20Regular,Arial/256-511.pbf", 22, &none), URL::Glyphs(vec!["Open Sans Regular".to_string(), "Arial".to_string()], "256-511".to_string()));
+        assert_eq!(parse_url("/fonts/Open%20Sans/0-100.pbf", 22, &none), URL::Invalid);
+        assert_eq!(parse_url("/fonts/..%2F..%2Fetc/0-255.pbf", 22, &none), URL::Invalid);
+
+        let aliases = Aliases::parse(r#"{"basemap": ["land", "points"]}"#).unwrap();
+        match parse_url("/basemap/2/1/3.pbf", 22, &aliases) {
+            URL::Tile(pathprefix, 2, 1, 3, _, None) => {
+                assert_eq!(pathprefix.parts(), vec!["land".to_string(), "points".to_string()]);
+                assert_eq!(pathprefix.alias(), Some("basemap"));
+                assert_eq!(pathprefix.path_with_trailing_slash(), "basemap/");
+            },
+            url => panic!("Unexpected {:?}", url),
+        }
+        assert_eq!(parse_url("/land__points/index.json", 22, &aliases), URL::Tilejson(URLPathPrefix::from_parts(vec!["land", "points"]), None, None));
 
     }
 }
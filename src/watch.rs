@@ -0,0 +1,315 @@
+extern crate inotify;
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime};
+
+use inotify::{Inotify, WatchMask, EventMask};
+
+/// Files modified less than this long ago might still be being written to, when we can't use
+/// inotify to know that they have been closed.
+const SETTLE_SECS: u64 = 2;
+
+/// Longest we will wait before retrying a directory which has errors
+const MAX_BACKOFF_SECS: u64 = 600;
+
+/// Something happened to a file in a watched directory
+#[derive(Debug)]
+enum WatchEvent {
+    /// The file has been opened & written to, but not closed yet
+    Writing(PathBuf),
+    /// The file has been closed after writing, or moved into the directory
+    Ready(PathBuf),
+    /// The file has been deleted, or moved out of the directory
+    Gone(PathBuf),
+}
+
+/// Waits for new files to appear in some directories. Uses Linux's inotify if possible, and falls
+/// back to polling (i.e. just sleeping) if not.
+pub struct DirectoryWatcher {
+    /// Events from the inotify thread. `None` if we are polling
+    events: Option<Receiver<WatchEvent>>,
+    /// Files which have been closed after writing (or moved in), and not written to since
+    ready: HashSet<PathBuf>,
+}
+
+impl DirectoryWatcher {
+    /// Start watching these directories
+    pub fn new(directories: &[&Path]) -> Self {
+        let events = match start_inotify(directories) {
+            Ok(events) => Some(events),
+            Err(e) => {
+                println!("Couldn't watch the expire directories with inotify, will check them every few seconds instead: {}", e);
+                None
+            },
+        };
+
+        DirectoryWatcher{ events: events, ready: HashSet::new() }
+    }
+
+    /// Don't use inotify, only poll
+    pub fn polling() -> Self {
+        DirectoryWatcher{ events: None, ready: HashSet::new() }
+    }
+
+    fn handle(&mut self, event: WatchEvent) {
+        match event {
+            WatchEvent::Writing(path) | WatchEvent::Gone(path) => { self.ready.remove(&path); },
+            WatchEvent::Ready(path) => { self.ready.insert(path); },
+        }
+    }
+
+    /// Stop using inotify, because its thread has stopped
+    fn disconnected(&mut self) {
+        println!("Stopped watching the expire directories with inotify, will check them every few seconds instead");
+        self.events = None;
+        self.ready.clear();
+    }
+
+    /// Catch up with everything that has happened since we last looked, without blocking. Call
+    /// this before using `is_ready`, so that files which started being written since then aren't
+    /// processed.
+    pub fn poll(&mut self) {
+        let mut disconnected = false;
+        let mut pending = Vec::new();
+        if let Some(ref events) = self.events {
+            loop {
+                match events.try_recv() {
+                    Ok(event) => { pending.push(event); },
+                    Err(TryRecvError::Empty) => { break; },
+                    Err(TryRecvError::Disconnected) => {
+                        disconnected = true;
+                        break;
+                    },
+                }
+            }
+        }
+        for event in pending {
+            self.handle(event);
+        }
+        if disconnected {
+            self.disconnected();
+        }
+    }
+
+    /// Block until a file is ready in one of the directories, or `timeout` passes (whichever is
+    /// first). Even with inotify, the timeout means that we still look at the directories now and
+    /// then, in case we missed an event.
+    pub fn wait(&mut self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            let event = match self.events {
+                None => {
+                    sleep(deadline - now);
+                    return;
+                },
+                Some(ref events) => events.recv_timeout(deadline - now),
+            };
+            match event {
+                Ok(WatchEvent::Ready(path)) => {
+                    self.ready.insert(path);
+                    break;
+                },
+                Ok(event) => { self.handle(event); },
+                Err(RecvTimeoutError::Timeout) => { break; },
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.disconnected();
+                    return;
+                },
+            }
+        }
+
+        self.poll();
+    }
+
+    /// Is this file (probably) completely written, and so can be processed? It is if inotify has
+    /// told us it was closed (or moved in). Otherwise (e.g. without inotify, if the event was
+    /// missed, or the file was there before we started), assume a file which hasn't been changed
+    /// recently has been finished.
+    pub fn is_ready(&self, path: &Path) -> bool {
+        if self.ready.contains(path) {
+            return true;
+        }
+        match path.metadata().and_then(|m| m.modified()).map(|mtime| SystemTime::now().duration_since(mtime)) {
+            Ok(Ok(age)) => age >= Duration::new(SETTLE_SECS, 0),
+            // mtime is in the future
+            Ok(Err(_)) => false,
+            // Can't tell, so let the caller try (and fail) to open it
+            Err(_) => true,
+        }
+    }
+}
+
+/// Start a thread which watches these directories, and sends back events for files in them
+fn start_inotify(directories: &[&Path]) -> Result<Receiver<WatchEvent>, String> {
+    let mut inotify = try!(Inotify::init().map_err(|e| e.to_string()));
+    let mut watched_directories = HashMap::new();
+    for directory in directories {
+        let wd = try!(inotify.add_watch(directory, WatchMask::CREATE | WatchMask::MODIFY | WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::MOVED_FROM | WatchMask::DELETE)
+                      .map_err(|e| format!("{:?}: {}", directory, e)));
+        watched_directories.insert(wd, directory.to_path_buf());
+    }
+
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            let events = match inotify.read_events_blocking(&mut buffer) {
+                Ok(events) => events,
+                Err(e) => {
+                    println!("Error reading inotify events: {}", e);
+                    return;
+                },
+            };
+            for event in events {
+                let (directory, name) = match (watched_directories.get(&event.wd), event.name) {
+                    (Some(directory), Some(name)) => (directory, name),
+                    _ => { continue; },
+                };
+                let path = directory.join(name);
+                let event = if event.mask.contains(EventMask::CLOSE_WRITE) || event.mask.contains(EventMask::MOVED_TO) {
+                    WatchEvent::Ready(path)
+                } else if event.mask.contains(EventMask::MOVED_FROM) || event.mask.contains(EventMask::DELETE) {
+                    WatchEvent::Gone(path)
+                } else {
+                    WatchEvent::Writing(path)
+                };
+                if sender.send(event).is_err() {
+                    // Nothing is listening anymore
+                    return;
+                }
+            }
+        }
+    });
+
+    Ok(receiver)
+}
+
+/// How long to wait before trying a directory again, after it has failed this many times in a
+/// row. Doubles every time, up to a maximum.
+pub fn backoff(base: Duration, consecutive_errors: u32) -> Duration {
+    let max = Duration::new(MAX_BACKOFF_SECS, 0);
+    if consecutive_errors == 0 {
+        return Duration::new(0, 0);
+    }
+    let multiplier = 1u32.checked_shl(consecutive_errors - 1).unwrap_or(u32::max_value());
+    match base.checked_mul(multiplier) {
+        Some(d) if d < max => d,
+        _ => max,
+    }
+}
+
+mod test {
+    #[test]
+    fn test_backoff() {
+        use super::backoff;
+        use std::time::Duration;
+
+        let base = Duration::new(5, 0);
+        assert_eq!(backoff(base, 0), Duration::new(0, 0));
+        assert_eq!(backoff(base, 1), Duration::new(5, 0));
+        assert_eq!(backoff(base, 2), Duration::new(10, 0));
+        assert_eq!(backoff(base, 4), Duration::new(40, 0));
+        assert_eq!(backoff(base, 10), Duration::new(600, 0));
+        assert_eq!(backoff(base, 100), Duration::new(600, 0));
+    }
+
+    #[test]
+    fn test_watcher() {
+        use super::DirectoryWatcher;
+        use std::fs;
+        use std::io::Write;
+        use std::env;
+        use std::time::{Duration, Instant};
+
+        let dir = env::temp_dir().join(format!("iompair-test-watch-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut watcher = DirectoryWatcher::new(&[&dir]);
+
+        let path = dir.join("expire-1.txt");
+        let mut file = fs::File::create(&path).unwrap();
+        file.write_all(b"1/0/0\n").unwrap();
+        file.flush().unwrap();
+        drop(file);
+
+        // Wakes up straight away, not after the timeout
+        let start = Instant::now();
+        watcher.wait(Duration::new(10, 0));
+        assert!(start.elapsed() < Duration::new(5, 0));
+        assert!(watcher.is_ready(&path));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_watcher_events() {
+        use super::{DirectoryWatcher, WatchEvent};
+        use std::collections::HashSet;
+        use std::fs;
+        use std::env;
+        use std::path::PathBuf;
+        use std::sync::mpsc::channel;
+        use std::thread;
+        use std::time::{Duration, Instant};
+        use filetime::{FileTime, set_file_times};
+
+        let dir = env::temp_dir().join(format!("iompair-test-watch-events-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (sender, receiver) = channel();
+        let mut watcher = DirectoryWatcher{ events: Some(receiver), ready: HashSet::new() };
+        let first = dir.join("expire-1.txt");
+        let second = dir.join("expire-2.txt");
+        fs::write(&first, "1/0/0\n").unwrap();
+
+        sender.send(WatchEvent::Ready(first.clone())).unwrap();
+        watcher.wait(Duration::new(10, 0));
+        assert!(watcher.is_ready(&first));
+
+        // While the first file was being processed, the second was created. Without an event for
+        // it, it's not ready until it hasn't been changed for a while
+        fs::write(&second, "1/0/0\n").unwrap();
+        watcher.poll();
+        assert!(! watcher.is_ready(&second));
+        sender.send(WatchEvent::Writing(second.clone())).unwrap();
+        watcher.poll();
+        assert!(! watcher.is_ready(&second));
+        sender.send(WatchEvent::Ready(second.clone())).unwrap();
+        watcher.poll();
+        assert!(watcher.is_ready(&second));
+        sender.send(WatchEvent::Writing(second.clone())).unwrap();
+        watcher.poll();
+        assert!(! watcher.is_ready(&second));
+
+        // If the close event is missed, it's ready once it has settled
+        let old = FileTime::from_seconds_since_1970(1_000_000_000, 0);
+        set_file_times(&second, old, old).unwrap();
+        assert!(watcher.is_ready(&second));
+
+        sender.send(WatchEvent::Gone(first.clone())).unwrap();
+        watcher.poll();
+        assert!(! watcher.ready.contains(&first));
+        fs::remove_dir_all(&dir).unwrap();
+
+        // A file being written to for a long time doesn't keep us waiting past the timeout
+        let writer = thread::spawn(move || {
+            for _ in 0..40 {
+                if sender.send(WatchEvent::Writing(PathBuf::from("/expire/3.txt"))).is_err() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+        let start = Instant::now();
+        watcher.wait(Duration::from_millis(300));
+        assert!(start.elapsed() < Duration::from_millis(1500));
+        drop(watcher);
+        writer.join().unwrap();
+    }
+}